@@ -0,0 +1 @@
+ALTER TABLE service_nwc DROP COLUMN wallet_key;
@@ -0,0 +1,2 @@
+-- secret for the wallet side of the connection, lets the proxy sign responses to the service
+ALTER TABLE service_nwc ADD COLUMN wallet_key TEXT;
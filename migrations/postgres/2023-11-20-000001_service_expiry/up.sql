@@ -0,0 +1 @@
+ALTER TABLE service_nwc ADD COLUMN expires_at TIMESTAMP;
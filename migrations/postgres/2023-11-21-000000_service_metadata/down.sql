@@ -0,0 +1,4 @@
+ALTER TABLE service_nwc DROP COLUMN labels;
+ALTER TABLE service_nwc DROP COLUMN app_pubkey;
+ALTER TABLE service_nwc DROP COLUMN icon_url;
+ALTER TABLE service_nwc DROP COLUMN description;
@@ -0,0 +1,5 @@
+ALTER TABLE service_nwc ADD COLUMN description TEXT;
+ALTER TABLE service_nwc ADD COLUMN icon_url TEXT;
+ALTER TABLE service_nwc ADD COLUMN app_pubkey TEXT;
+-- json array of strings
+ALTER TABLE service_nwc ADD COLUMN labels TEXT NOT NULL DEFAULT '[]';
@@ -0,0 +1,3 @@
+ALTER TABLE service_nwc ADD COLUMN max_payment_sats BIGINT;
+ALTER TABLE service_nwc ADD COLUMN budget_sats BIGINT;
+ALTER TABLE service_nwc ADD COLUMN budget_renewal TEXT;
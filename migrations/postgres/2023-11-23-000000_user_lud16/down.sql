@@ -0,0 +1,2 @@
+ALTER TABLE user_nwc DROP COLUMN lud16;
+ALTER TABLE users DROP COLUMN lud16;
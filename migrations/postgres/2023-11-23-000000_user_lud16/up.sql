@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN lud16 TEXT;
+ALTER TABLE user_nwc ADD COLUMN lud16 TEXT;
@@ -0,0 +1 @@
+ALTER TABLE user_nwc DROP COLUMN fallback_relays;
@@ -0,0 +1,2 @@
+-- json array of relay urls, tried when the wallet's relay is unreachable
+ALTER TABLE user_nwc ADD COLUMN fallback_relays TEXT NOT NULL DEFAULT '[]';
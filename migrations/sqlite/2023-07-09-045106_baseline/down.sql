@@ -0,0 +1,3 @@
+DROP TABLE service_nwc;
+DROP TABLE user_nwc;
+DROP TABLE users;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE service_nwc DROP COLUMN expires_at;
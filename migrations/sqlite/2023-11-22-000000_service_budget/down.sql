@@ -0,0 +1,3 @@
+ALTER TABLE service_nwc DROP COLUMN budget_renewal;
+ALTER TABLE service_nwc DROP COLUMN budget_sats;
+ALTER TABLE service_nwc DROP COLUMN max_payment_sats;
@@ -0,0 +1,20 @@
+-- payments made through service connections, used for budgets and relaying responses
+CREATE TABLE payments
+(
+    id              TEXT PRIMARY KEY NOT NULL,
+    service_key     TEXT             NOT NULL,
+    request_id      TEXT             NOT NULL,
+    forward_id      TEXT             NOT NULL,
+    d_tag           TEXT,
+    invoice         TEXT,
+    payment_hash    TEXT,
+    amount_msats    BIGINT           NOT NULL,
+    status          TEXT             NOT NULL,
+    preimage        TEXT,
+    fees_paid_msats BIGINT,
+    date_created    TIMESTAMP        NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    date_settled    TIMESTAMP,
+    FOREIGN KEY (service_key) REFERENCES service_nwc (request_key) ON DELETE CASCADE
+);
+create index payments_service_key_index on payments (service_key, date_created);
+create index payments_forward_id_index on payments (forward_id);
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN username TEXT;
+CREATE UNIQUE INDEX users_username_uindex ON users (username);
@@ -0,0 +1,13 @@
+-- invoices made for zap requests to users' lightning addresses, waiting on a receipt
+CREATE TABLE zaps
+(
+    payment_hash TEXT PRIMARY KEY NOT NULL,
+    user_pubkey  TEXT             NOT NULL,
+    invoice      TEXT             NOT NULL,
+    request      TEXT             NOT NULL,
+    receipt_id   TEXT,
+    expires_at   TIMESTAMP        NOT NULL,
+    date_created TIMESTAMP        NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_pubkey) REFERENCES users (pubkey) ON DELETE CASCADE
+);
+create index zaps_expires_at_index on zaps (expires_at);
@@ -0,0 +1,167 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+
+use crate::config::{Command, ServicesCommand, UsersCommand, WalletsCommand};
+use crate::encryption::{write_private_file, MasterKey};
+use crate::export::{export, import, Export};
+use crate::models::service_nwc::ServiceNwc;
+use crate::models::user::{validate_lud16, User};
+use crate::models::user_nwc::UserNwc;
+use crate::models::DbConnection;
+
+/// Runs an admin command directly against the database
+pub fn run(
+    command: Command,
+    conn: &mut DbConnection,
+    master_key: &MasterKey,
+) -> anyhow::Result<()> {
+    match command {
+        Command::Serve => Err(anyhow!("serve is not an admin command")),
+        Command::Users { command } => match command {
+            UsersCommand::List => {
+                for user in User::get_all(conn)? {
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        user.pubkey(),
+                        user.date_created(),
+                        user.lud16().unwrap_or_default(),
+                        user.username().unwrap_or_default()
+                    );
+                }
+                Ok(())
+            }
+            UsersCommand::SetLud16 { pubkey, lud16 } => {
+                if let Some(lud16) = &lud16 {
+                    validate_lud16(lud16)?;
+                }
+                if !User::set_lud16(conn, &pubkey, lud16.as_deref())? {
+                    return Err(anyhow!("No user {pubkey}"));
+                }
+                println!("Updated {pubkey}");
+                Ok(())
+            }
+        },
+        Command::Services { command } => match command {
+            ServicesCommand::List { user } => {
+                let services = match user {
+                    Some(user) => ServiceNwc::find_by_user(conn, &user)?,
+                    None => ServiceNwc::get_all(conn)?,
+                };
+                for service in services {
+                    let expires_at = match service.expires_at() {
+                        Some(expires_at) if service.is_expired() => format!("expired {expires_at}"),
+                        Some(expires_at) => expires_at.to_string(),
+                        None => String::from("never"),
+                    };
+                    let metadata = service.metadata();
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        service.request_key(),
+                        service.user_pubkey(),
+                        service.service_name(),
+                        service.relay_url(),
+                        service.date_created(),
+                        expires_at,
+                        metadata.description.unwrap_or_default(),
+                        metadata.labels.join(",")
+                    );
+                }
+                Ok(())
+            }
+            ServicesCommand::Revoke { request_key } => {
+                if ServiceNwc::delete(conn, &request_key)? {
+                    println!("Revoked service connection {request_key}");
+                    Ok(())
+                } else {
+                    Err(anyhow!("No service connection found for {request_key}"))
+                }
+            }
+            ServicesCommand::Edit {
+                request_key,
+                description,
+                icon_url,
+                app_pubkey,
+                labels,
+            } => {
+                let service = ServiceNwc::find_by_request_key(conn, &request_key)?
+                    .ok_or_else(|| anyhow!("No service connection found for {request_key}"))?;
+
+                let mut metadata = service.metadata();
+                if description.is_some() {
+                    metadata.description = description;
+                }
+                if icon_url.is_some() {
+                    metadata.icon_url = icon_url;
+                }
+                if app_pubkey.is_some() {
+                    metadata.app_pubkey = app_pubkey;
+                }
+                if let Some(labels) = labels {
+                    metadata.labels = labels;
+                }
+                metadata.validate()?;
+
+                ServiceNwc::update_metadata(conn, &request_key, &metadata)?;
+                println!("Updated service connection {request_key}");
+                Ok(())
+            }
+        },
+        Command::Wallets { command } => match command {
+            WalletsCommand::List { user } => {
+                let wallets = match user {
+                    Some(user) => UserNwc::find_by_user(conn, &user)?,
+                    None => UserNwc::get_all(conn)?,
+                };
+                for wallet in wallets {
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        wallet.request_key(),
+                        wallet.user_pubkey(),
+                        wallet.relay_url(),
+                        wallet.date_created()
+                    );
+                }
+                Ok(())
+            }
+        },
+        Command::Stats => {
+            println!("users: {}", User::count(conn)?);
+            println!("wallets: {}", UserNwc::count(conn)?);
+            println!("service connections: {}", ServiceNwc::count(conn)?);
+            println!("relays: {}", UserNwc::get_relays(conn)?.len());
+            Ok(())
+        }
+        Command::Export { output, passphrase } => {
+            let export = export(conn, master_key, passphrase.as_deref())?;
+            let json = serde_json::to_string_pretty(&export)?;
+            match output {
+                Some(path) => {
+                    write_private_file(Path::new(&path), &json)?;
+                    eprintln!(
+                        "Exported {} users, {} wallets, {} service connections and {} payments to {path}",
+                        export.users.len(),
+                        export.user_wallets.len(),
+                        export.service_connections.len(),
+                        export.payments.len()
+                    );
+                }
+                None => println!("{json}"),
+            }
+            if export.encryption.is_none() {
+                eprintln!("Warning: the export contains unencrypted NWC secrets");
+            }
+            Ok(())
+        }
+        Command::Import { input, passphrase } => {
+            let json = std::fs::read_to_string(input)?;
+            let export: Export = serde_json::from_str(&json)?;
+            let summary = import(conn, master_key, export, passphrase.as_deref())?;
+            println!(
+                "Imported {} users, {} wallets, {} service connections and {} payments",
+                summary.users, summary.user_wallets, summary.service_connections, summary.payments
+            );
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! A NIP-47 wallet service for developing services against the proxy without a real wallet.
+//! It prints an NWC uri to give to `/set-user-nwc` and answers requests on a local relay,
+//! nothing is actually paid.
+
+use std::time::Duration;
+
+use clap::Parser;
+use nostr::nips::nip47::ErrorCode;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+use nwc_proxy::mock::wallet::{scripted, Behaviour, MockWallet};
+use nwc_proxy::nwc::NwcMethod;
+
+/// Methods the wallet advertises in its info event
+const METHODS: [NwcMethod; 5] = [
+    NwcMethod::PayInvoice,
+    NwcMethod::MultiPayInvoice,
+    NwcMethod::PayKeysend,
+    NwcMethod::MakeInvoice,
+    NwcMethod::GetBalance,
+];
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Mock NWC wallet for local development")]
+struct Cli {
+    /// Relay the wallet listens on
+    #[arg(
+        long,
+        env = "NWC_MOCK_WALLET_RELAY",
+        default_value = "ws://localhost:7000"
+    )]
+    relay: String,
+    /// Methods answered with an error instead of succeeding
+    #[arg(long = "fail", value_delimiter = ',')]
+    fail: Vec<NwcMethod>,
+    /// NIP-47 error code for failed requests
+    #[arg(long, default_value = "INTERNAL", value_parser = parse_error_code)]
+    error_code: ErrorCode,
+    /// Methods that are never answered, so requests time out
+    #[arg(long = "ignore", value_delimiter = ',')]
+    ignore: Vec<NwcMethod>,
+    /// Milliseconds to wait before answering each request
+    #[arg(long, default_value_t = 0)]
+    latency_ms: u64,
+    /// Balance returned by get_balance
+    #[arg(long, default_value_t = 100_000)]
+    balance_sats: u64,
+}
+
+fn parse_error_code(s: &str) -> Result<ErrorCode, String> {
+    serde_json::from_value(serde_json::Value::String(s.to_uppercase()))
+        .map_err(|_| format!("Unknown error code {s}"))
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .with_writer(std::io::stderr)
+        .init();
+
+    let behaviour = Behaviour {
+        fail: cli.fail,
+        error_code: cli.error_code,
+        ignore: cli.ignore,
+        balance_msats: cli.balance_sats * 1_000,
+    };
+    let latency = Duration::from_millis(cli.latency_ms);
+    let wallet = MockWallet::start(&cli.relay, &METHODS, scripted(behaviour), latency).await?;
+
+    // the uri alone goes to stdout so it can be piped into a request to the proxy
+    info!("Mock wallet listening on {}", cli.relay);
+    println!("{}", wallet.nwc_uri());
+
+    tokio::signal::ctrl_c().await?;
+    info!("Received {} requests", wallet.requests().len());
+    wallet.stop().await
+}
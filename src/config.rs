@@ -1,16 +1,620 @@
-use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use anyhow::anyhow;
+use axum::http::{header, HeaderName, HeaderValue, Method};
+
+use bitcoin::secp256k1::PublicKey;
+use clap::{Parser, Subcommand};
+use nostr::key::XOnlyPublicKey;
+use nostr::Url;
+use serde::Deserialize;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::models::service_nwc::{BudgetRenewal, SpendingLimits, DEFAULT_SERVICE_RELAY};
+use crate::tls::TlsConfig;
+
+pub const CONFIG_FILE: &str = "config.toml";
+
+// Every setting can also be given as a `NWC_PROXY_*` environment variable or in the
+// config file, precedence is command line, then environment, then config file, then defaults.
 #[derive(Parser, Debug, Clone)]
 #[command(version, author, about)]
 /// A tool for zapping based on reactions to notes.
+pub struct Cli {
+    #[clap(long, env = "NWC_PROXY_DATA_DIR", global = true)]
+    /// Location of database and keys files [default: .]
+    pub data_dir: Option<String>,
+    #[clap(long, env = "NWC_PROXY_CONFIG", global = true)]
+    /// Config file to read [default: config.toml in the data dir]
+    pub config: Option<String>,
+    #[clap(long, env = "NWC_PROXY_BIND")]
+    /// Bind address for zap-tunnel's webserver [default: 0.0.0.0]
+    pub bind: Option<String>,
+    #[clap(long, env = "NWC_PROXY_PORT")]
+    /// Port for zap-tunnel's webserver [default: 3000]
+    pub port: Option<u16>,
+    #[clap(long, env = "DATABASE_URL", global = true)]
+    /// Database to use, postgres:// urls use PostgreSQL, defaults to db.sqlite in the data dir
+    pub database_url: Option<String>,
+    #[clap(long, env = "NWC_PROXY_MASTER_KEY", hide_env_values = true)]
+    /// Hex encoded key used to encrypt stored NWC secrets, defaults to the master.key file in the data dir
+    pub master_key: Option<String>,
+    #[clap(long, env = "NWC_PROXY_LOG_LEVEL")]
+    /// Log level or filter directives, e.g. `debug` or `nwc_proxy=debug,nostr_sdk=warn` [default: info]
+    pub log_level: Option<String>,
+    #[clap(
+        long,
+        env = "NWC_PROXY_LOG_JSON",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    /// Output logs as JSON, `--log-json=false` turns it off when the config file enables it
+    pub log_json: Option<bool>,
+    #[clap(long, env = "NWC_PROXY_TLS_CERT", requires = "tls_key")]
+    /// PEM certificate chain, serves the API over HTTPS when set together with --tls-key
+    pub tls_cert: Option<PathBuf>,
+    #[clap(long, env = "NWC_PROXY_TLS_KEY", requires = "tls_cert")]
+    /// PEM private key for --tls-cert
+    pub tls_key: Option<PathBuf>,
+    #[clap(long, env = "NWC_PROXY_PUBLIC_URL")]
+    /// Url the proxy is reachable at, enables lightning addresses at its domain
+    pub public_url: Option<String>,
+    #[clap(long, env = "NWC_PROXY_SERVICE_RELAY")]
+    /// Relay put in the NWC uris given to services [default: wss://relay.damus.io]
+    pub service_relay: Option<String>,
+    #[clap(long = "relay", env = "NWC_PROXY_RELAYS", value_delimiter = ',')]
+    /// Additional relays to listen on
+    pub relays: Vec<String>,
+    #[clap(long, env = "NWC_PROXY_DB_POOL_SIZE")]
+    /// Maximum number of database connections [default: 16]
+    pub db_pool_size: Option<u32>,
+    #[clap(long, env = "NWC_PROXY_DB_TIMEOUT")]
+    /// Seconds to wait on a busy database [default: 30]
+    pub db_timeout: Option<u64>,
+    #[clap(long, env = "NWC_PROXY_REQUEST_TIMEOUT")]
+    /// Seconds before handling a nostr event is abandoned [default: 30]
+    pub request_timeout: Option<u64>,
+    #[clap(long, env = "NWC_PROXY_WORKERS")]
+    /// Most nostr events handled at once, must be less than db_pool_size [default: 8]
+    pub workers: Option<usize>,
+    #[clap(long, env = "NWC_PROXY_QUEUE_SIZE")]
+    /// Most nostr events waiting for a worker before the proxy stops reading from relays [default: 256]
+    pub queue_size: Option<usize>,
+    #[clap(
+        long = "cors-origin",
+        env = "NWC_PROXY_CORS_ORIGINS",
+        value_delimiter = ','
+    )]
+    /// Origins allowed to call the API from a browser
+    pub cors_origins: Vec<String>,
+    #[clap(
+        long = "cors-method",
+        env = "NWC_PROXY_CORS_METHODS",
+        value_delimiter = ','
+    )]
+    /// Methods allowed for cross origin requests [default: GET,POST]
+    pub cors_methods: Vec<String>,
+    #[clap(
+        long = "cors-header",
+        env = "NWC_PROXY_CORS_HEADERS",
+        value_delimiter = ','
+    )]
+    /// Request headers allowed for cross origin requests [default: content-type,authorization]
+    pub cors_headers: Vec<String>,
+    #[clap(
+        long,
+        env = "NWC_PROXY_CORS_ALLOW_ANY_ORIGIN",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    /// Allow any website to call the API, only use this for development.
+    /// `--cors-allow-any-origin=false` turns it off when the config file enables it
+    pub cors_allow_any_origin: Option<bool>,
+    #[clap(long, env = "NWC_PROXY_MAX_PAYMENT_SATS")]
+    /// Default maximum amount for a single payment
+    pub max_payment_sats: Option<u64>,
+    #[clap(long, env = "NWC_PROXY_BUDGET_SATS")]
+    /// Default budget for each service connection
+    pub budget_sats: Option<u64>,
+    #[clap(long, env = "NWC_PROXY_BUDGET_RENEWAL", value_parser = parse_budget_renewal)]
+    /// How often the default budget renews: daily, weekly, monthly, yearly or never [default: never]
+    pub budget_renewal: Option<BudgetRenewal>,
+    #[clap(
+        long = "blocked-payee",
+        env = "NWC_PROXY_BLOCKED_PAYEES",
+        value_delimiter = ','
+    )]
+    /// Lightning node pubkeys services are not allowed to pay
+    pub blocked_payees: Vec<PublicKey>,
+    #[command(subcommand)]
+    /// Command to run, defaults to `serve`
+    pub command: Option<Command>,
+}
+
+fn parse_budget_renewal(s: &str) -> anyhow::Result<BudgetRenewal> {
+    BudgetRenewal::from_str(s)
+}
+
+/// Settings read from the TOML config file
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub bind: Option<String>,
+    pub port: Option<u16>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub database_url: Option<String>,
+    pub log_level: Option<String>,
+    pub log_json: Option<bool>,
+    pub public_url: Option<String>,
+    pub service_relay: Option<String>,
+    pub relays: Option<Vec<String>>,
+    pub db_pool_size: Option<u32>,
+    pub db_timeout: Option<u64>,
+    pub request_timeout: Option<u64>,
+    pub workers: Option<usize>,
+    pub queue_size: Option<usize>,
+    pub cors_origins: Option<Vec<String>>,
+    pub cors_methods: Option<Vec<String>>,
+    pub cors_headers: Option<Vec<String>>,
+    pub cors_allow_any_origin: Option<bool>,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+}
+
+/// Defaults for the spending policy of service connections
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Maximum amount for a single payment
+    pub max_payment_sats: Option<u64>,
+    /// Maximum total of payments per budget period
+    pub budget_sats: Option<u64>,
+    pub budget_renewal: Option<BudgetRenewal>,
+    /// Lightning node pubkeys that can't be paid, by invoice or keysend
+    #[serde(default)]
+    pub blocked_payees: Vec<PublicKey>,
+}
+
+impl FileConfig {
+    /// Reads the config file, a missing file is the same as an empty one
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = std::fs::read_to_string(path)?;
+        toml::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Invalid config file {}: {e}", path.display()))
+    }
+}
+
+/// Which browser origins may call the API
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CorsConfig {
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+    pub allow_any_origin: bool,
+}
+
+impl CorsConfig {
+    /// Builds the layer, no cross origin requests are allowed unless origins are listed
+    /// or any origin is explicitly allowed.
+    pub fn layer(&self) -> anyhow::Result<CorsLayer> {
+        let origins = if self.allow_any_origin {
+            if !self.origins.is_empty() {
+                return Err(anyhow!(
+                    "cors_origins can not be combined with cors_allow_any_origin"
+                ));
+            }
+            AllowOrigin::any()
+        } else {
+            if self.origins.iter().any(|o| o == "*") {
+                return Err(anyhow!("use cors_allow_any_origin instead of a `*` origin"));
+            }
+            let origins = self
+                .origins
+                .iter()
+                .map(|o| HeaderValue::from_str(o.trim_end_matches('/')))
+                .collect::<Result<Vec<_>, _>>()?;
+            AllowOrigin::list(origins)
+        };
+
+        let methods = if self.methods.is_empty() {
+            vec![Method::GET, Method::POST]
+        } else {
+            self.methods
+                .iter()
+                .map(|m| Method::from_str(&m.to_uppercase()))
+                .collect::<Result<_, _>>()?
+        };
+
+        let headers = if self.headers.is_empty() {
+            vec![header::CONTENT_TYPE, header::AUTHORIZATION]
+        } else {
+            self.headers
+                .iter()
+                .map(|h| HeaderName::from_str(h))
+                .collect::<Result<_, _>>()?
+        };
+
+        Ok(CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers))
+    }
+}
+
+/// Fully resolved configuration
+#[derive(Debug, Clone)]
 pub struct Config {
-    #[clap(default_value_t = String::from("."), long)]
-    /// Location of database and keys files
     pub data_dir: String,
-    #[clap(default_value_t = String::from("0.0.0.0"), long)]
-    /// Bind address for zap-tunnel's webserver
     pub bind: String,
-    #[clap(default_value_t = 3000, long)]
-    /// Port for zap-tunnel's webserver
     pub port: u16,
+    pub tls: Option<TlsConfig>,
+    pub database_url: Option<String>,
+    pub master_key: Option<String>,
+    pub log_level: String,
+    pub log_json: bool,
+    /// Base url for LNURL callbacks, lightning addresses are disabled without it
+    pub public_url: Option<Url>,
+    pub service_relay: String,
+    pub relays: Vec<String>,
+    pub db_pool_size: u32,
+    pub db_timeout: u64,
+    pub request_timeout: u64,
+    /// Most nostr events handled at once
+    pub workers: usize,
+    /// Most nostr events waiting for a worker
+    pub queue_size: usize,
+    pub cors: CorsConfig,
+    pub policy: PolicyConfig,
+    pub command: Option<Command>,
+}
+
+impl Config {
+    /// Parses the command line and environment, then fills in the rest from the config file
+    pub fn load() -> anyhow::Result<Self> {
+        let cli = Cli::parse();
+        let data_dir = cli.data_dir.clone().unwrap_or_else(|| String::from("."));
+        let path = match &cli.config {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(&data_dir).join(CONFIG_FILE),
+        };
+        let file = FileConfig::read(&path)?;
+
+        Self::resolve(cli, file, data_dir)
+    }
+
+    fn resolve(cli: Cli, file: FileConfig, data_dir: String) -> anyhow::Result<Self> {
+        let list = |cli: Vec<String>, file: Option<Vec<String>>| {
+            if cli.is_empty() {
+                file.unwrap_or_default()
+            } else {
+                cli
+            }
+        };
+
+        let public_url = match cli.public_url.or(file.public_url) {
+            Some(url) => {
+                let parsed =
+                    Url::parse(&url).map_err(|e| anyhow!("Invalid public url {url}: {e}"))?;
+                if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+                    return Err(anyhow!("Public url must be an http(s) url, got {url}"));
+                }
+                Some(parsed)
+            }
+            None => None,
+        };
+
+        // workers use database connections while they handle events, leave some for the api
+        let db_pool_size = cli.db_pool_size.or(file.db_pool_size).unwrap_or(16);
+        let workers = cli.workers.or(file.workers).unwrap_or(8);
+        if workers == 0 {
+            return Err(anyhow!("workers must be at least 1"));
+        }
+        if workers >= db_pool_size as usize {
+            return Err(anyhow!(
+                "workers ({workers}) must be less than db_pool_size ({db_pool_size})"
+            ));
+        }
+
+        let policy = PolicyConfig {
+            max_payment_sats: cli.max_payment_sats.or(file.policy.max_payment_sats),
+            budget_sats: cli.budget_sats.or(file.policy.budget_sats),
+            budget_renewal: cli.budget_renewal.or(file.policy.budget_renewal),
+            blocked_payees: if cli.blocked_payees.is_empty() {
+                file.policy.blocked_payees
+            } else {
+                cli.blocked_payees
+            },
+        };
+        SpendingLimits {
+            max_payment_sats: policy.max_payment_sats,
+            budget_sats: policy.budget_sats,
+            budget_renewal: policy.budget_renewal,
+        }
+        .validate()?;
+
+        // the cert and key always come from the same source
+        let tls = match (cli.tls_cert, cli.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            _ => match (file.tls_cert, file.tls_key) {
+                (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+                (None, None) => None,
+                _ => return Err(anyhow!("tls_cert and tls_key must be set together")),
+            },
+        };
+
+        Ok(Self {
+            data_dir,
+            bind: cli
+                .bind
+                .or(file.bind)
+                .unwrap_or_else(|| String::from("0.0.0.0")),
+            port: cli.port.or(file.port).unwrap_or(3000),
+            tls,
+            database_url: cli.database_url.or(file.database_url),
+            master_key: cli.master_key,
+            log_level: cli
+                .log_level
+                .or(file.log_level)
+                .unwrap_or_else(|| String::from("info")),
+            log_json: cli.log_json.or(file.log_json).unwrap_or(false),
+            public_url,
+            service_relay: cli
+                .service_relay
+                .or(file.service_relay)
+                .unwrap_or_else(|| String::from(DEFAULT_SERVICE_RELAY)),
+            relays: list(cli.relays, file.relays),
+            db_pool_size,
+            db_timeout: cli.db_timeout.or(file.db_timeout).unwrap_or(30),
+            request_timeout: cli.request_timeout.or(file.request_timeout).unwrap_or(30),
+            workers,
+            queue_size: cli.queue_size.or(file.queue_size).unwrap_or(256),
+            cors: CorsConfig {
+                origins: list(cli.cors_origins, file.cors_origins),
+                methods: list(cli.cors_methods, file.cors_methods),
+                headers: list(cli.cors_headers, file.cors_headers),
+                allow_any_origin: cli
+                    .cors_allow_any_origin
+                    .or(file.cors_allow_any_origin)
+                    .unwrap_or(false),
+            },
+            policy,
+            command: cli.command,
+        })
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Run the proxy server
+    Serve,
+    /// Inspect users
+    Users {
+        #[command(subcommand)]
+        command: UsersCommand,
+    },
+    /// Inspect and manage service connections
+    Services {
+        #[command(subcommand)]
+        command: ServicesCommand,
+    },
+    /// Inspect the wallets users have connected
+    Wallets {
+        #[command(subcommand)]
+        command: WalletsCommand,
+    },
+    /// Print database statistics
+    Stats,
+    /// Export users, wallets and service connections as JSON
+    Export {
+        #[clap(long, short)]
+        /// File to write to, defaults to stdout
+        output: Option<String>,
+        #[clap(long, env = "NWC_PROXY_EXPORT_PASSPHRASE", hide_env_values = true)]
+        /// Encrypt the NWC secrets in the export with this passphrase
+        passphrase: Option<String>,
+    },
+    /// Import a JSON export into the database
+    Import {
+        /// File to read the export from
+        input: String,
+        #[clap(long, env = "NWC_PROXY_EXPORT_PASSPHRASE", hide_env_values = true)]
+        /// Passphrase the export was encrypted with
+        passphrase: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum UsersCommand {
+    /// List all users
+    List,
+    /// Set the lightning address put in a user's service uris, omit it to clear
+    SetLud16 {
+        pubkey: PublicKey,
+        lud16: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ServicesCommand {
+    /// List service connections
+    List {
+        #[clap(long)]
+        /// Only list connections for this user
+        user: Option<PublicKey>,
+    },
+    /// Revoke a service connection so its requests are no longer forwarded
+    Revoke {
+        /// Pubkey of the service connection, as in its NWC uri
+        request_key: XOnlyPublicKey,
+    },
+    /// Edit the metadata of a service connection, unset options are left unchanged
+    Edit {
+        /// Pubkey of the service connection, as in its NWC uri
+        request_key: XOnlyPublicKey,
+        #[clap(long)]
+        description: Option<String>,
+        #[clap(long)]
+        icon_url: Option<String>,
+        #[clap(long)]
+        /// Pubkey of the app that requested the connection
+        app_pubkey: Option<XOnlyPublicKey>,
+        #[clap(long = "label")]
+        /// Replaces all labels, can be repeated
+        labels: Option<Vec<String>>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum WalletsCommand {
+    /// List connected wallets
+    List {
+        #[clap(long)]
+        /// Only list wallets for this user
+        user: Option<PublicKey>,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_precedence() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            bind = "127.0.0.1"
+            port = 4000
+            relays = ["wss://file.relay"]
+            request_timeout = 10
+            log_json = true
+            cors_allow_any_origin = true
+
+            [policy]
+            max_payment_sats = 1000
+            budget_renewal = "daily"
+            blocked_payees = ["02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619"]
+            "#,
+        )
+        .unwrap();
+
+        // file values are used when nothing else is set
+        let cli = Cli::parse_from(["nwc-proxy"]);
+        let config = Config::resolve(cli, file.clone(), String::from(".")).unwrap();
+        assert_eq!(config.bind, "127.0.0.1");
+        assert_eq!(config.port, 4000);
+        assert_eq!(config.relays, vec!["wss://file.relay".to_string()]);
+        assert_eq!(config.request_timeout, 10);
+        assert_eq!(config.policy.max_payment_sats, Some(1000));
+        assert_eq!(config.policy.budget_renewal, Some(BudgetRenewal::Daily));
+        assert_eq!(config.policy.blocked_payees.len(), 1);
+        assert_eq!(config.db_pool_size, 16);
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.service_relay, DEFAULT_SERVICE_RELAY);
+        assert!(config.log_json);
+        assert!(config.cors.allow_any_origin);
+
+        // command line overrides the file
+        let cli = Cli::parse_from([
+            "nwc-proxy",
+            "--port",
+            "5000",
+            "--relay",
+            "wss://cli.relay",
+            "--max-payment-sats",
+            "50",
+            "--log-json=false",
+            "--cors-allow-any-origin=false",
+        ]);
+        let config = Config::resolve(cli, file, String::from(".")).unwrap();
+        assert_eq!(config.bind, "127.0.0.1");
+        assert_eq!(config.port, 5000);
+        assert_eq!(config.relays, vec!["wss://cli.relay".to_string()]);
+        assert_eq!(config.policy.max_payment_sats, Some(50));
+        assert!(!config.log_json);
+        assert!(!config.cors.allow_any_origin);
+
+        // a bare flag still turns it on
+        let cli = Cli::parse_from([
+            "nwc-proxy",
+            "--log-json",
+            "--cors-allow-any-origin",
+            "users",
+            "list",
+        ]);
+        assert_eq!(cli.log_json, Some(true));
+        assert_eq!(cli.cors_allow_any_origin, Some(true));
+    }
+
+    #[test]
+    fn test_cors() {
+        // nothing is allowed by default
+        let cors = CorsConfig::default();
+        assert!(cors.layer().is_ok());
+
+        let cors = CorsConfig {
+            origins: vec!["https://example.com/".to_string()],
+            methods: vec!["get".to_string()],
+            headers: vec!["Authorization".to_string()],
+            allow_any_origin: false,
+        };
+        assert!(cors.layer().is_ok());
+
+        let invalid = CorsConfig {
+            methods: vec!["not a method".to_string()],
+            ..cors.clone()
+        };
+        assert!(invalid.layer().is_err());
+
+        // allowing any origin is exclusive with a list
+        let conflicting = CorsConfig {
+            allow_any_origin: true,
+            ..cors
+        };
+        assert!(conflicting.layer().is_err());
+    }
+
+    #[test]
+    fn test_tls_requires_cert_and_key() {
+        let file: FileConfig = toml::from_str(r#"tls_cert = "cert.pem""#).unwrap();
+        let cli = Cli::parse_from(["nwc-proxy"]);
+        assert!(Config::resolve(cli, file, String::from(".")).is_err());
+
+        assert!(Cli::try_parse_from(["nwc-proxy", "--tls-key", "key.pem"]).is_err());
+    }
+
+    #[test]
+    fn test_workers_fit_db_pool() {
+        let file: FileConfig = toml::from_str("db_pool_size = 4").unwrap();
+        let cli = Cli::parse_from(["nwc-proxy", "--workers", "3"]);
+        assert!(Config::resolve(cli, file.clone(), String::from(".")).is_ok());
+
+        // the api needs connections too
+        let cli = Cli::parse_from(["nwc-proxy", "--workers", "4"]);
+        assert!(Config::resolve(cli, file, String::from(".")).is_err());
+    }
+
+    #[test]
+    fn test_public_url() {
+        let file: FileConfig = toml::from_str(r#"public_url = "https://pay.example.com""#).unwrap();
+        let cli = Cli::parse_from(["nwc-proxy"]);
+        let config = Config::resolve(cli, file, String::from(".")).unwrap();
+        let public_url = config.public_url.unwrap();
+        assert_eq!(public_url.host_str(), Some("pay.example.com"));
+
+        let cli = Cli::parse_from(["nwc-proxy", "--public-url", "ftp://example.com"]);
+        assert!(Config::resolve(cli, FileConfig::default(), String::from(".")).is_err());
+    }
+
+    #[test]
+    fn test_unknown_file_keys_rejected() {
+        assert!(toml::from_str::<FileConfig>("prot = 3000").is_err());
+    }
 }
@@ -0,0 +1,112 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::anyhow;
+use argon2::Argon2;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+pub const MASTER_KEY_FILE: &str = "master.key";
+
+const NONCE_LEN: usize = 24;
+
+/// Symmetric key used to encrypt the NWC secrets we store in the database.
+#[derive(Clone)]
+pub struct MasterKey {
+    cipher: XChaCha20Poly1305,
+}
+
+impl MasterKey {
+    pub fn from_hex(hex: &str) -> anyhow::Result<Self> {
+        let bytes: Vec<u8> = FromHex::from_hex(hex.trim())?;
+        if bytes.len() != 32 {
+            return Err(anyhow!("master key must be 32 bytes"));
+        }
+
+        let cipher =
+            XChaCha20Poly1305::new_from_slice(&bytes).map_err(|_| anyhow!("invalid master key"))?;
+        Ok(Self { cipher })
+    }
+
+    /// Derives a key from a passphrase, used for encrypting exports
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> anyhow::Result<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| anyhow!("could not derive key from passphrase: {e}"))?;
+
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        })
+    }
+
+    pub fn generate() -> (Self, String) {
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        let hex = key.to_hex();
+        let cipher = XChaCha20Poly1305::new(&key);
+        (Self { cipher }, hex)
+    }
+
+    /// Uses the given hex key if provided, otherwise reads the key file in the data dir,
+    /// creating a new one if it does not exist yet.
+    pub fn load_or_create(data_dir: &Path, hex: Option<&str>) -> anyhow::Result<Self> {
+        if let Some(hex) = hex {
+            return Self::from_hex(hex);
+        }
+
+        let path = data_dir.join(MASTER_KEY_FILE);
+        if path.exists() {
+            let hex = std::fs::read_to_string(&path)?;
+            return Self::from_hex(&hex);
+        }
+
+        let (key, hex) = Self::generate();
+        write_private_file(&path, &hex)?;
+
+        Ok(key)
+    }
+
+    /// Encrypts the given bytes, returns hex encoded nonce || ciphertext
+    pub fn encrypt(&self, plaintext: &[u8]) -> String {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .expect("encryption failed");
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend(ciphertext);
+        bytes.to_hex()
+    }
+
+    pub fn decrypt(&self, encrypted: &str) -> anyhow::Result<Vec<u8>> {
+        let bytes: Vec<u8> = FromHex::from_hex(encrypted)?;
+        if bytes.len() <= NONCE_LEN {
+            return Err(anyhow!("encrypted data too short"));
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("could not decrypt, wrong master key?"))
+    }
+}
+
+/// Creates a file that only the current user can read, failing if it already exists
+pub(crate) fn write_private_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // set the mode on creation so the contents are never readable by others, even briefly
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+
+    Ok(())
+}
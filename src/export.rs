@@ -0,0 +1,564 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::secp256k1::{PublicKey, Secp256k1};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use chrono::NaiveDateTime;
+use diesel::Connection;
+use nostr::key::{SecretKey, XOnlyPublicKey};
+use nostr::nips::nip47::NostrWalletConnectURI;
+use nostr::{EventId, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::encryption::MasterKey;
+use crate::models::payment::{Payment, PaymentStatus};
+use crate::models::service_nwc::{ServiceMetadata, ServiceNwc, SpendingLimits};
+use crate::models::user::{validate_lud16, validate_username, User};
+use crate::models::user_nwc::UserNwc;
+use crate::models::DbConnection;
+use crate::spending::MAX_AMOUNT_MSATS;
+
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Portable snapshot of the proxy database
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Export {
+    pub version: u32,
+    /// Set when the NWC uris are encrypted under a passphrase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<ExportEncryption>,
+    pub users: Vec<ExportUser>,
+    pub user_wallets: Vec<ExportUserWallet>,
+    pub service_connections: Vec<ExportServiceConnection>,
+    /// Payments made by the service connections, so budgets carry over
+    #[serde(default)]
+    pub payments: Vec<ExportPayment>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExportEncryption {
+    pub kdf: String,
+    pub salt: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExportUser {
+    pub pubkey: PublicKey,
+    pub date_created: NaiveDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lud16: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExportUserWallet {
+    pub user_pubkey: PublicKey,
+    /// NWC uri, or the hex encoded ciphertext of it if the export is encrypted
+    pub nwc: String,
+    pub date_created: NaiveDateTime,
+    /// Other relays the wallet listed, tried when its main relay is unreachable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback_relays: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExportServiceConnection {
+    pub user_pubkey: PublicKey,
+    pub service_name: String,
+    /// NWC uri, or the hex encoded ciphertext of it if the export is encrypted
+    pub nwc: String,
+    pub date_created: NaiveDateTime,
+    /// Hex secret of the wallet side of the connection, encrypted like `nwc`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wallet_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<NaiveDateTime>,
+    #[serde(flatten)]
+    pub metadata: ServiceMetadata,
+    #[serde(flatten)]
+    pub limits: SpendingLimits,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExportPayment {
+    /// Request key of the service connection that made the payment
+    pub service_key: XOnlyPublicKey,
+    pub request_id: EventId,
+    pub forward_id: EventId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub d_tag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invoice: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment_hash: Option<String>,
+    pub amount_msats: u64,
+    pub status: PaymentStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preimage: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fees_paid_msats: Option<u64>,
+    pub date_created: NaiveDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_settled: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub users: usize,
+    pub user_wallets: usize,
+    pub service_connections: usize,
+    pub payments: usize,
+}
+
+const KDF_ARGON2ID: &str = "argon2id";
+
+/// Exports everything in the database, optionally encrypting the NWC secrets with a passphrase
+pub fn export(
+    conn: &mut DbConnection,
+    master_key: &MasterKey,
+    passphrase: Option<&str>,
+) -> anyhow::Result<Export> {
+    let (encryption, export_key) = match passphrase {
+        Some(passphrase) => {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let key = MasterKey::from_passphrase(passphrase, &salt)?;
+            let encryption = ExportEncryption {
+                kdf: KDF_ARGON2ID.to_string(),
+                salt: salt.to_hex(),
+            };
+            (Some(encryption), Some(key))
+        }
+        None => (None, None),
+    };
+
+    let seal = |plaintext: String| match &export_key {
+        Some(key) => key.encrypt(plaintext.as_bytes()),
+        None => plaintext,
+    };
+
+    let users = User::get_all(conn)?
+        .into_iter()
+        .map(|user| ExportUser {
+            pubkey: user.pubkey(),
+            date_created: user.date_created(),
+            lud16: user.lud16().map(String::from),
+            username: user.username().map(String::from),
+        })
+        .collect();
+
+    let mut user_wallets = vec![];
+    for wallet in UserNwc::get_all(conn)? {
+        user_wallets.push(ExportUserWallet {
+            user_pubkey: wallet.user_pubkey(),
+            nwc: seal(wallet.nwc_uri(master_key)?.to_string()),
+            date_created: wallet.date_created(),
+            fallback_relays: wallet
+                .fallback_relays()
+                .iter()
+                .map(Url::to_string)
+                .collect(),
+        });
+    }
+
+    let mut service_connections = vec![];
+    for service in ServiceNwc::get_all(conn)? {
+        let wallet_key = service
+            .wallet_keys(master_key)?
+            .map(|keys| {
+                keys.secret_key()
+                    .map(|key| seal(key.display_secret().to_string()))
+            })
+            .transpose()?;
+        service_connections.push(ExportServiceConnection {
+            user_pubkey: service.user_pubkey(),
+            service_name: service.service_name().to_string(),
+            nwc: seal(service.nwc_uri(master_key, None)?.to_string()),
+            date_created: service.date_created(),
+            wallet_key,
+            expires_at: service.expires_at(),
+            metadata: service.metadata(),
+            limits: service.limits(),
+        });
+    }
+
+    let payments = Payment::get_all(conn)?
+        .into_iter()
+        .map(|payment| ExportPayment {
+            service_key: payment.service_key(),
+            request_id: payment.request_id(),
+            forward_id: payment.forward_id(),
+            d_tag: payment.d_tag().map(String::from),
+            invoice: payment.invoice().map(String::from),
+            payment_hash: payment.payment_hash().map(String::from),
+            amount_msats: payment.amount_msats(),
+            status: payment.status(),
+            preimage: payment.preimage().map(String::from),
+            fees_paid_msats: payment.fees_paid_msats(),
+            date_created: payment.date_created(),
+            date_settled: payment.date_settled(),
+        })
+        .collect();
+
+    Ok(Export {
+        version: EXPORT_VERSION,
+        encryption,
+        users,
+        user_wallets,
+        service_connections,
+        payments,
+    })
+}
+
+/// Validates the whole export then imports it in a single transaction,
+/// nothing is written if any record is invalid or already exists.
+pub fn import(
+    conn: &mut DbConnection,
+    master_key: &MasterKey,
+    export: Export,
+    passphrase: Option<&str>,
+) -> anyhow::Result<ImportSummary> {
+    if export.version != EXPORT_VERSION {
+        return Err(anyhow!("Unsupported export version {}", export.version));
+    }
+
+    let export_key = match (&export.encryption, passphrase) {
+        (None, _) => None,
+        (Some(_), None) => return Err(anyhow!("Export is encrypted, a passphrase is required")),
+        (Some(encryption), Some(passphrase)) => {
+            if encryption.kdf != KDF_ARGON2ID {
+                return Err(anyhow!("Unsupported kdf {}", encryption.kdf));
+            }
+            let salt: Vec<u8> = FromHex::from_hex(&encryption.salt)?;
+            Some(MasterKey::from_passphrase(passphrase, &salt)?)
+        }
+    };
+
+    let open = |sealed: &str| -> anyhow::Result<String> {
+        match &export_key {
+            Some(key) => Ok(String::from_utf8(key.decrypt(sealed)?)?),
+            None => Ok(sealed.to_string()),
+        }
+    };
+    let open_nwc = |nwc: &str| -> anyhow::Result<NostrWalletConnectURI> {
+        NostrWalletConnectURI::from_str(&open(nwc)?).map_err(|e| anyhow!("Invalid NWC uri: {e}"))
+    };
+
+    let users: HashSet<PublicKey> = export.users.iter().map(|u| u.pubkey).collect();
+    if users.len() != export.users.len() {
+        return Err(anyhow!("Duplicate users in export"));
+    }
+    for user in &export.users {
+        if let Some(lud16) = &user.lud16 {
+            validate_lud16(lud16)?;
+        }
+        if let Some(username) = &user.username {
+            validate_username(username)?;
+        }
+    }
+
+    let mut wallets = Vec::with_capacity(export.user_wallets.len());
+    for wallet in export.user_wallets {
+        if !users.contains(&wallet.user_pubkey) {
+            return Err(anyhow!("Wallet for unknown user {}", wallet.user_pubkey));
+        }
+        let fallback_relays = wallet
+            .fallback_relays
+            .iter()
+            .map(|relay| Url::parse(relay).map_err(|e| anyhow!("Invalid relay {relay}: {e}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        wallets.push((open_nwc(&wallet.nwc)?, fallback_relays, wallet));
+    }
+
+    let mut services = Vec::with_capacity(export.service_connections.len());
+    for service in export.service_connections {
+        if !users.contains(&service.user_pubkey) {
+            return Err(anyhow!("Service for unknown user {}", service.user_pubkey));
+        }
+        service.metadata.validate()?;
+        service.limits.validate()?;
+        let nwc = open_nwc(&service.nwc)?;
+        let wallet_key = match &service.wallet_key {
+            Some(sealed) => {
+                let key = SecretKey::from_str(&open(sealed)?)?;
+                if key.x_only_public_key(&Secp256k1::new()).0 != nwc.public_key {
+                    return Err(anyhow!("Wallet key does not match NWC uri"));
+                }
+                Some(key)
+            }
+            None => None,
+        };
+        services.push(
+            ServiceNwc::from_nwc_uri(
+                &nwc,
+                service.user_pubkey,
+                service.service_name,
+                master_key,
+                service.date_created,
+            )
+            .with_wallet_key(wallet_key, master_key)
+            .with_expiry(service.expires_at)
+            .with_metadata(&service.metadata)
+            .with_limits(&service.limits),
+        );
+    }
+
+    let service_keys: HashSet<XOnlyPublicKey> = services.iter().map(|s| s.request_key()).collect();
+    let mut payments = Vec::with_capacity(export.payments.len());
+    for payment in export.payments {
+        if !service_keys.contains(&payment.service_key) {
+            return Err(anyhow!(
+                "Payment for unknown service connection {}",
+                payment.service_key
+            ));
+        }
+        if payment.amount_msats > MAX_AMOUNT_MSATS {
+            return Err(anyhow!("Payment amount is too large"));
+        }
+        payments.push(
+            Payment::new(
+                payment.service_key,
+                payment.request_id,
+                payment.forward_id,
+                payment.d_tag,
+                payment.invoice,
+                payment.payment_hash,
+                payment.amount_msats,
+            )
+            .created_at(payment.date_created)
+            .with_result(
+                payment.status,
+                payment.preimage,
+                payment.fees_paid_msats,
+                payment.date_settled,
+            ),
+        );
+    }
+
+    conn.transaction(|conn| {
+        for user in &export.users {
+            User::create_at(conn, user.pubkey, user.date_created)?;
+            if let Some(lud16) = &user.lud16 {
+                User::set_lud16(conn, &user.pubkey, Some(lud16))?;
+            }
+            if let Some(username) = &user.username {
+                User::set_username(conn, &user.pubkey, Some(username))?;
+            }
+        }
+        for (nwc, fallback_relays, wallet) in &wallets {
+            UserNwc::create_at(
+                conn,
+                nwc.clone(),
+                wallet.user_pubkey,
+                master_key,
+                wallet.date_created,
+            )?;
+            if !fallback_relays.is_empty() {
+                UserNwc::set_fallback_relays(conn, &nwc.public_key, fallback_relays)?;
+            }
+        }
+        for service in &services {
+            ServiceNwc::insert(conn, service)?;
+        }
+        for payment in &payments {
+            Payment::insert(conn, payment)?;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    Ok(ImportSummary {
+        users: export.users.len(),
+        user_wallets: wallets.len(),
+        service_connections: services.len(),
+        payments: payments.len(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::service_nwc::{BudgetRenewal, DEFAULT_SERVICE_RELAY};
+    use crate::models::test::{create_database, gen_tmp_db_name, teardown_database};
+
+    const PUB_KEY_STR: &str = "032e58afe51f9ed8ad3cc7897f634d881fdbe49a81564629ded8156bebd2ffd1af";
+    const NWC_URI_STR: &str = "nostr+walletconnect://5fa11a95186e2bdc05e047d8573721b407aaa54e5c39f93b2811f176a65ac5f8?relay=wss%3A%2F%2Fnostr.mutinywallet.com%2F&secret=e0d196bf4af30401332085702d35ec0c0b6d6bcc43b76d05d9d9898b2c2c6d94";
+
+    fn populate(conn: &mut DbConnection, master_key: &MasterKey) {
+        let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
+        User::create(conn, pk).unwrap();
+        User::set_lud16(conn, &pk, Some("satoshi@example.com")).unwrap();
+        User::set_username(conn, &pk, Some("satoshi")).unwrap();
+        let nwc = NostrWalletConnectURI::from_str(NWC_URI_STR).unwrap();
+        UserNwc::create(conn, nwc.clone(), pk, master_key).unwrap();
+        let fallback = Url::parse("wss://relay.example.com").unwrap();
+        UserNwc::set_fallback_relays(conn, &nwc.public_key, &[fallback]).unwrap();
+        let service =
+            ServiceNwc::generate(pk, "service".to_string(), DEFAULT_SERVICE_RELAY, master_key)
+                .with_expiry(Some(
+                    chrono::Utc::now().naive_utc() + chrono::Duration::days(1),
+                ))
+                .with_metadata(&ServiceMetadata {
+                    description: Some("Zap bot on laptop".to_string()),
+                    labels: vec!["bots".to_string()],
+                    ..Default::default()
+                })
+                .with_limits(&SpendingLimits {
+                    budget_sats: Some(10_000),
+                    budget_renewal: Some(BudgetRenewal::Weekly),
+                    ..Default::default()
+                });
+        ServiceNwc::insert(conn, &service).unwrap();
+
+        // one payment settled and one still waiting on the wallet
+        let request_id = EventId::from_slice(&[1; 32]).unwrap();
+        let forward_id = EventId::from_slice(&[2; 32]).unwrap();
+        let payment = |amount_msats| {
+            Payment::new(
+                service.request_key(),
+                request_id,
+                forward_id,
+                None,
+                None,
+                None,
+                amount_msats,
+            )
+        };
+        let settled = payment(3_000);
+        Payment::insert(conn, &settled).unwrap();
+        Payment::settle(conn, settled.id(), Some("00".repeat(32)), Some(5)).unwrap();
+        Payment::insert(conn, &payment(2_000)).unwrap();
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        for passphrase in [None, Some("hunter2")] {
+            let src_name = gen_tmp_db_name();
+            let src = &mut create_database(&src_name);
+            let src_key = MasterKey::generate().0;
+            populate(src, &src_key);
+
+            let exported = export(src, &src_key, passphrase).unwrap();
+            assert_eq!(exported.encryption.is_some(), passphrase.is_some());
+            let json = serde_json::to_string(&exported).unwrap();
+            if passphrase.is_some() {
+                assert!(!json.contains("nostr+walletconnect"));
+            }
+
+            // import into a fresh database with a different master key
+            let dst_name = gen_tmp_db_name();
+            let dst = &mut create_database(&dst_name);
+            let dst_key = MasterKey::generate().0;
+            let parsed: Export = serde_json::from_str(&json).unwrap();
+            let summary = import(dst, &dst_key, parsed, passphrase).unwrap();
+            assert_eq!(summary.users, 1);
+            assert_eq!(summary.user_wallets, 1);
+            assert_eq!(summary.service_connections, 1);
+            assert_eq!(summary.payments, 2);
+
+            let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
+            let src_service = &ServiceNwc::find_by_user(src, &pk).unwrap()[0];
+            let dst_service = &ServiceNwc::find_by_user(dst, &pk).unwrap()[0];
+            assert_eq!(
+                src_service.nwc_uri(&src_key, None).unwrap(),
+                dst_service.nwc_uri(&dst_key, None).unwrap()
+            );
+            assert_eq!(
+                src_service
+                    .wallet_keys(&src_key)
+                    .unwrap()
+                    .map(|k| k.public_key()),
+                dst_service
+                    .wallet_keys(&dst_key)
+                    .unwrap()
+                    .map(|k| k.public_key())
+            );
+            assert_eq!(src_service.expires_at(), dst_service.expires_at());
+            assert_eq!(src_service.metadata(), dst_service.metadata());
+            assert_eq!(src_service.limits(), dst_service.limits());
+
+            // the connection has spent as much of its budget as before
+            let service_key = dst_service.request_key();
+            assert_eq!(
+                Payment::spent_msats(dst, &service_key, None).unwrap(),
+                5_000
+            );
+            let history = |payments: Vec<Payment>| {
+                let mut history = payments
+                    .into_iter()
+                    .map(|p| {
+                        (
+                            p.amount_msats(),
+                            p.status(),
+                            p.preimage().map(String::from),
+                            p.fees_paid_msats(),
+                            p.date_created(),
+                            p.date_settled(),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                history.sort_by_key(|(amount_msats, ..)| *amount_msats);
+                history
+            };
+            assert_eq!(
+                history(Payment::get_all(src).unwrap()),
+                history(Payment::get_all(dst).unwrap())
+            );
+            assert_eq!(
+                User::lightning_address(dst, &pk).unwrap().as_deref(),
+                Some("satoshi@example.com")
+            );
+            assert!(User::find_by_username(dst, "satoshi").unwrap().is_some());
+            assert_eq!(
+                UserNwc::find_by_user(src, &pk).unwrap()[0].relays(),
+                UserNwc::find_by_user(dst, &pk).unwrap()[0].relays()
+            );
+
+            teardown_database(&src_name);
+            teardown_database(&dst_name);
+        }
+    }
+
+    #[test]
+    fn test_import_rejects_invalid() {
+        let src_name = gen_tmp_db_name();
+        let src = &mut create_database(&src_name);
+        let master_key = MasterKey::generate().0;
+        populate(src, &master_key);
+
+        let exported = export(src, &master_key, Some("hunter2")).unwrap();
+
+        let dst_name = gen_tmp_db_name();
+        let dst = &mut create_database(&dst_name);
+
+        // wrong or missing passphrase
+        assert!(import(dst, &master_key, exported.clone(), None).is_err());
+        assert!(import(dst, &master_key, exported.clone(), Some("wrong")).is_err());
+
+        // invalid nwc uri
+        let mut invalid = export(src, &master_key, None).unwrap();
+        invalid.user_wallets[0].nwc = "nostr+walletconnect://invalid".to_string();
+        assert!(import(dst, &master_key, invalid, None).is_err());
+
+        // payment for a connection that isn't in the export
+        let mut invalid = export(src, &master_key, None).unwrap();
+        invalid.payments[0].service_key = nostr::Keys::generate().public_key();
+        assert!(import(dst, &master_key, invalid, None).is_err());
+
+        // nothing should have been written
+        let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
+        assert!(User::find(dst, &pk).unwrap().is_none());
+
+        // importing twice fails and rolls back
+        import(dst, &master_key, exported.clone(), Some("hunter2")).unwrap();
+        assert!(import(dst, &master_key, exported, Some("hunter2")).is_err());
+        assert_eq!(User::count(dst).unwrap(), 1);
+
+        teardown_database(&src_name);
+        teardown_database(&dst_name);
+    }
+}
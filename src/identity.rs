@@ -0,0 +1,114 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use bitcoin::hashes::hex::ToHex;
+use nostr::key::{SecretKey, XOnlyPublicKey};
+use nostr::{Event, EventBuilder, Keys, Metadata, Url};
+
+use crate::encryption::write_private_file;
+
+pub const IDENTITY_KEY_FILE: &str = "identity.key";
+
+/// Loads the proxy's nostr identity from the data dir, creating one on first start
+/// so the proxy keeps the same pubkey across restarts.
+pub fn load_or_create_identity(data_dir: &Path) -> anyhow::Result<Keys> {
+    let path = data_dir.join(IDENTITY_KEY_FILE);
+    if path.exists() {
+        let hex = std::fs::read_to_string(&path)?;
+        let secret = SecretKey::from_str(hex.trim())?;
+        return Ok(Keys::new(secret));
+    }
+
+    let keys = Keys::generate();
+    let hex = keys.secret_key()?.secret_bytes().to_hex();
+    write_private_file(&path, &hex)?;
+
+    Ok(keys)
+}
+
+/// The proxy's kind 0 profile, so clients can show who its DMs and zap receipts come from
+pub fn metadata_event(identity: &Keys, public_url: Option<&Url>) -> anyhow::Result<Event> {
+    let mut metadata = Metadata::new()
+        .name("nwc-proxy")
+        .about("Nostr Wallet Connect proxy");
+    if let Some(public_url) = public_url {
+        metadata = metadata.website(public_url.clone());
+    }
+
+    Ok(EventBuilder::set_metadata(metadata).to_event(identity)?)
+}
+
+/// A NIP-04 direct message from the proxy
+pub fn direct_message(
+    identity: &Keys,
+    receiver: XOnlyPublicKey,
+    message: &str,
+) -> anyhow::Result<Event> {
+    let builder = EventBuilder::new_encrypted_direct_msg(identity, receiver, message, None)?;
+    Ok(builder.to_event(identity)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nostr::prelude::decrypt;
+    use nostr::Kind;
+
+    #[test]
+    fn test_identity_persists() {
+        let dir = std::env::temp_dir().join(format!("nwc_proxy_{}", Keys::generate().public_key()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let first = load_or_create_identity(&dir).unwrap();
+        let second = load_or_create_identity(&dir).unwrap();
+        assert_eq!(first.public_key(), second.public_key());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(dir.join(IDENTITY_KEY_FILE)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_metadata_event() {
+        let identity = Keys::generate();
+        let public_url = Url::parse("https://proxy.example.com").unwrap();
+        let event = metadata_event(&identity, Some(&public_url)).unwrap();
+        event.verify().unwrap();
+        assert_eq!(event.kind, Kind::Metadata);
+        assert_eq!(event.pubkey, identity.public_key());
+
+        let metadata = Metadata::from_json(&event.content).unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("nwc-proxy"));
+        assert_eq!(
+            metadata.website.as_deref(),
+            Some("https://proxy.example.com/")
+        );
+    }
+
+    #[test]
+    fn test_direct_message() {
+        let identity = Keys::generate();
+        let receiver = Keys::generate();
+        let event = direct_message(&identity, receiver.public_key(), "hello").unwrap();
+        event.verify().unwrap();
+        assert_eq!(event.kind, Kind::EncryptedDirectMessage);
+        assert_eq!(event.pubkey, identity.public_key());
+        assert_eq!(
+            event.tags,
+            vec![nostr::Tag::PubKey(receiver.public_key(), None)]
+        );
+
+        let decrypted = decrypt(
+            &receiver.secret_key().unwrap(),
+            &identity.public_key(),
+            &event.content,
+        )
+        .unwrap();
+        assert_eq!(decrypted, "hello");
+    }
+}
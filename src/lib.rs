@@ -0,0 +1,102 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use diesel::connection::SimpleConnection;
+use nostr::key::XOnlyPublicKey;
+use nostr::Keys;
+use tokio::sync::watch::Sender;
+
+use crate::config::Config;
+use crate::encryption::MasterKey;
+use crate::metrics::Metrics;
+use crate::models::{DbConnection, DbPool};
+use crate::subscriber::SubscriberStatus;
+
+pub mod admin;
+pub mod config;
+pub mod encryption;
+mod export;
+pub mod identity;
+pub mod lnurl;
+mod metrics;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod models;
+pub mod nwc;
+mod relay_health;
+pub mod routes;
+mod spending;
+pub mod subscriber;
+pub mod tls;
+pub mod zaps;
+
+#[derive(Clone)]
+pub struct State {
+    pubkeys: Arc<Mutex<Sender<Vec<XOnlyPublicKey>>>>,
+    db_pool: DbPool,
+    master_key: MasterKey,
+    metrics: Metrics,
+    subscriber: SubscriberStatus,
+    config: Arc<Config>,
+    /// The proxy's own nostr keys, used to authenticate to relays and sign zap receipts
+    identity: Keys,
+    /// Publishes zap receipts, kept apart from the subscriber as senders pick the relays
+    zap_client: nostr_sdk::Client,
+}
+
+impl State {
+    /// `pubkeys` is told about every key the subscriber should start watching
+    pub fn new(
+        db_pool: DbPool,
+        master_key: MasterKey,
+        config: Config,
+        identity: Keys,
+        pubkeys: Sender<Vec<XOnlyPublicKey>>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            pubkeys: Arc::new(Mutex::new(pubkeys)),
+            db_pool,
+            master_key,
+            metrics: Metrics::new()?,
+            subscriber: SubscriberStatus::default(),
+            config: Arc::new(config),
+            zap_client: zaps::receipt_client(&identity),
+            identity,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionOptions {
+    pub enable_wal: bool,
+    pub enable_foreign_keys: bool,
+    pub busy_timeout: Option<Duration>,
+}
+
+impl diesel::r2d2::CustomizeConnection<DbConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), diesel::r2d2::Error> {
+        // these options only apply to SQLite
+        let conn = match conn {
+            DbConnection::Sqlite(conn) => conn,
+            DbConnection::Postgres(_) => return Ok(()),
+        };
+
+        (|| {
+            if self.enable_wal {
+                conn.batch_execute("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+            }
+            if self.enable_foreign_keys {
+                conn.batch_execute("PRAGMA foreign_keys = ON;")?;
+            }
+            if let Some(d) = self.busy_timeout {
+                conn.batch_execute(&format!("PRAGMA busy_timeout = {};", d.as_millis()))?;
+            }
+            Ok(())
+        })()
+        .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
@@ -0,0 +1,337 @@
+//! LNURL-pay server, so users get a lightning address at the proxy's domain that is paid
+//! with invoices made by their own wallet. Zaps are supported, see [`crate::zaps`].
+
+use crate::models::user::User;
+use crate::models::user_nwc::UserNwc;
+use crate::models::zap::Zap;
+use crate::nwc::{MakeInvoiceParams, NwcMethod, NwcRequest};
+use crate::zaps::{validate_zap_request, MAX_PENDING_ZAPS};
+use crate::State;
+use anyhow::anyhow;
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::{sha256, Hash};
+use chrono::NaiveDateTime;
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription, Sha256};
+use nostr::Url;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const MIN_SENDABLE_MSATS: u64 = 1_000;
+const MAX_SENDABLE_MSATS: u64 = 10_000_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PayRequest {
+    pub callback: String,
+    pub min_sendable: u64,
+    pub max_sendable: u64,
+    pub metadata: String,
+    pub tag: String,
+    /// NIP-57, the proxy publishes zap receipts for users
+    pub allows_nostr: bool,
+    pub nostr_pubkey: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CallbackParams {
+    /// Amount in msats
+    pub amount: u64,
+    /// A NIP-57 zap request event
+    pub nostr: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CallbackResponse {
+    pub pr: String,
+    pub routes: Vec<String>,
+}
+
+/// Errors in the LNURL format, `{"status": "ERROR", "reason": ...}`
+#[derive(Debug)]
+pub enum LnurlError {
+    Disabled,
+    UnknownUser,
+    BadRequest(String),
+    /// The user's wallet couldn't make an invoice
+    Wallet(anyhow::Error),
+    /// Database and other errors on our side, only logged as callers can't do anything about them
+    Internal(anyhow::Error),
+}
+
+impl IntoResponse for LnurlError {
+    fn into_response(self) -> Response {
+        let (status, reason) = match self {
+            LnurlError::Disabled => (
+                StatusCode::NOT_FOUND,
+                "Lightning addresses are not enabled".to_string(),
+            ),
+            LnurlError::UnknownUser => (StatusCode::NOT_FOUND, "Unknown user".to_string()),
+            LnurlError::BadRequest(reason) => (StatusCode::BAD_REQUEST, reason),
+            LnurlError::Wallet(e) => (StatusCode::BAD_GATEWAY, e.to_string()),
+            LnurlError::Internal(e) => {
+                error!("Error handling LNURL request: {e}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal error".to_string(),
+                )
+            }
+        };
+        let body = serde_json::json!({ "status": "ERROR", "reason": reason });
+        (status, Json(body)).into_response()
+    }
+}
+
+impl From<anyhow::Error> for LnurlError {
+    fn from(e: anyhow::Error) -> Self {
+        LnurlError::Internal(e)
+    }
+}
+
+/// The user's address on the proxy's domain
+pub fn lightning_address(public_url: &Url, username: &str) -> String {
+    format!("{username}@{}", public_url.host_str().unwrap_or_default())
+}
+
+fn callback_url(public_url: &Url, username: &str) -> String {
+    format!(
+        "{}/lnurlp/{username}/callback",
+        public_url.as_str().trim_end_matches('/')
+    )
+}
+
+/// LUD-06 metadata, its hash is committed to in the invoice's description hash
+fn metadata(address: &str) -> String {
+    serde_json::json!([
+        ["text/plain", format!("Payment to {address}")],
+        ["text/identifier", address],
+    ])
+    .to_string()
+}
+
+fn find_user(state: &State, username: &str) -> Result<(Url, User), LnurlError> {
+    let public_url = state
+        .config
+        .public_url
+        .clone()
+        .ok_or(LnurlError::Disabled)?;
+    let conn = &mut state.db_pool.get().map_err(anyhow::Error::from)?;
+    let user = User::find_by_username(conn, username)
+        .map_err(anyhow::Error::from)?
+        .ok_or(LnurlError::UnknownUser)?;
+
+    Ok((public_url, user))
+}
+
+pub(crate) fn pay_request_impl(state: &State, username: &str) -> Result<PayRequest, LnurlError> {
+    let (public_url, _) = find_user(state, username)?;
+
+    Ok(PayRequest {
+        callback: callback_url(&public_url, username),
+        min_sendable: MIN_SENDABLE_MSATS,
+        max_sendable: MAX_SENDABLE_MSATS,
+        metadata: metadata(&lightning_address(&public_url, username)),
+        tag: "payRequest".to_string(),
+        allows_nostr: true,
+        nostr_pubkey: state.identity.public_key().to_string(),
+    })
+}
+
+pub async fn pay_request(
+    Extension(state): Extension<State>,
+    Path(username): Path<String>,
+) -> Result<Json<PayRequest>, LnurlError> {
+    pay_request_impl(&state, &username).map(Json)
+}
+
+pub(crate) async fn callback_impl(
+    state: &State,
+    username: &str,
+    amount_msats: u64,
+    zap_request: Option<String>,
+) -> Result<CallbackResponse, LnurlError> {
+    let (public_url, user) = find_user(state, username)?;
+    if !(MIN_SENDABLE_MSATS..=MAX_SENDABLE_MSATS).contains(&amount_msats) {
+        return Err(LnurlError::BadRequest(format!(
+            "Amount must be between {MIN_SENDABLE_MSATS} and {MAX_SENDABLE_MSATS} msats"
+        )));
+    }
+    if let Some(zap_request) = &zap_request {
+        validate_zap_request(zap_request, amount_msats)
+            .map_err(|e| LnurlError::BadRequest(e.to_string()))?;
+
+        // anyone can zap, so cap the unpaid zaps we poll the user's wallet for
+        let conn = &mut state.db_pool.get().map_err(anyhow::Error::from)?;
+        let now = chrono::Utc::now().naive_utc();
+        let pending = Zap::count_pending(conn, &user.pubkey(), now).map_err(anyhow::Error::from)?;
+        if pending >= MAX_PENDING_ZAPS {
+            warn!(username, pending, "Too many unpaid zaps");
+            return Err(LnurlError::BadRequest(
+                "Too many unpaid zaps, try again later".to_string(),
+            ));
+        }
+    }
+
+    let user_nwc = {
+        let conn = &mut state.db_pool.get().map_err(anyhow::Error::from)?;
+        UserNwc::find_by_user(conn, &user.pubkey())
+            .map_err(anyhow::Error::from)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| LnurlError::BadRequest("User has no wallet connected".to_string()))?
+    };
+
+    // zap invoices commit to the zap request instead of the metadata
+    let description = zap_request
+        .clone()
+        .unwrap_or_else(|| metadata(&lightning_address(&public_url, username)));
+    let description_hash = sha256::Hash::hash(description.as_bytes());
+    let params = MakeInvoiceParams {
+        amount: amount_msats,
+        description_hash: Some(description_hash.to_hex()),
+        ..Default::default()
+    };
+    let req = NwcRequest::new(NwcMethod::MakeInvoice, params);
+
+    state
+        .metrics
+        .requests_forwarded
+        .with_label_values(&[NwcMethod::MakeInvoice.as_str()])
+        .inc();
+    let timeout = Duration::from_secs(state.config.request_timeout);
+    let response = state
+        .subscriber
+        .request_wallet(state, &user_nwc, &req, timeout)
+        .await
+        .map_err(LnurlError::Wallet)?;
+
+    if let Some(error) = response.error {
+        warn!(username, "Wallet could not make invoice: {}", error.message);
+        return Err(LnurlError::Wallet(anyhow!(
+            "Wallet could not make invoice: {}",
+            error.message
+        )));
+    }
+    let invoice = response
+        .result
+        .as_ref()
+        .and_then(|result| result["invoice"].as_str())
+        .ok_or_else(|| LnurlError::Wallet(anyhow!("Wallet did not return an invoice")))?;
+
+    let parsed = check_invoice(invoice, amount_msats, description_hash)?;
+
+    if let Some(zap_request) = zap_request {
+        let expires_at = parsed
+            .expires_at()
+            .and_then(|at| NaiveDateTime::from_timestamp_opt(at.as_secs() as i64, 0))
+            .ok_or_else(|| LnurlError::Wallet(anyhow!("Wallet returned an invalid invoice")))?;
+        let zap = Zap::new(
+            parsed.payment_hash().to_hex(),
+            user.pubkey(),
+            invoice.to_string(),
+            zap_request,
+            expires_at,
+        );
+        let conn = &mut state.db_pool.get().map_err(anyhow::Error::from)?;
+        Zap::insert(conn, &zap).map_err(anyhow::Error::from)?;
+    }
+
+    info!(username, amount_msats, "Made invoice for lightning address");
+
+    Ok(CallbackResponse {
+        pr: invoice.to_string(),
+        routes: vec![],
+    })
+}
+
+/// Checks the wallet's invoice is for the amount and description we asked for. The payer checks
+/// this too, but don't hand out an invoice we know is wrong
+fn check_invoice(
+    invoice: &str,
+    amount_msats: u64,
+    description_hash: sha256::Hash,
+) -> Result<Bolt11Invoice, LnurlError> {
+    let parsed = Bolt11Invoice::from_str(invoice)
+        .map_err(|_| LnurlError::Wallet(anyhow!("Wallet returned an invalid invoice")))?;
+    if parsed.amount_milli_satoshis() != Some(amount_msats) {
+        return Err(LnurlError::Wallet(anyhow!(
+            "Wallet returned an invoice for the wrong amount"
+        )));
+    }
+    // LUD-06 and NIP-57 both need the invoice to commit to the description's hash
+    if parsed.description() != Bolt11InvoiceDescription::Hash(&Sha256(description_hash)) {
+        return Err(LnurlError::Wallet(anyhow!(
+            "Wallet returned an invoice for the wrong description"
+        )));
+    }
+
+    Ok(parsed)
+}
+
+pub async fn callback(
+    Extension(state): Extension<State>,
+    Path(username): Path<String>,
+    Query(params): Query<CallbackParams>,
+) -> Result<Json<CallbackResponse>, LnurlError> {
+    callback_impl(&state, &username, params.amount, params.nostr)
+        .await
+        .map(Json)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::wallet::{create_invoice, create_invoice_with_hash};
+
+    #[test]
+    fn test_pay_request_metadata() {
+        let public_url = Url::parse("https://pay.example.com/").unwrap();
+        let address = lightning_address(&public_url, "satoshi");
+        assert_eq!(address, "satoshi@pay.example.com");
+        assert_eq!(
+            callback_url(&public_url, "satoshi"),
+            "https://pay.example.com/lnurlp/satoshi/callback"
+        );
+
+        let metadata: Vec<Vec<String>> = serde_json::from_str(&metadata(&address)).unwrap();
+        assert_eq!(
+            metadata[1],
+            vec!["text/identifier", "satoshi@pay.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_check_invoice() {
+        let description_hash = sha256::Hash::hash(b"metadata");
+        let invoice = create_invoice_with_hash(10_000, Some(description_hash))
+            .unwrap()
+            .to_string();
+        let parsed = check_invoice(&invoice, 10_000, description_hash).unwrap();
+        assert_eq!(parsed.to_string(), invoice);
+
+        // wrong amount
+        assert!(check_invoice(&invoice, 20_000, description_hash).is_err());
+        // committing to something else
+        let other = sha256::Hash::hash(b"other");
+        assert!(check_invoice(&invoice, 10_000, other).is_err());
+        // a plain description instead of the hash
+        let plain = create_invoice(10_000).unwrap().to_string();
+        assert!(check_invoice(&plain, 10_000, description_hash).is_err());
+        assert!(check_invoice("lnbc1", 10_000, description_hash).is_err());
+    }
+
+    #[test]
+    fn test_error_responses() {
+        let response = LnurlError::BadRequest("Invalid zap request".to_string()).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // internal errors don't reach the caller
+        let response = LnurlError::from(anyhow!("connection refused")).into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
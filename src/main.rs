@@ -1,70 +1,79 @@
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use axum::http::{Method, StatusCode, Uri};
-use axum::routing::post;
-use axum::{http, Extension, Router};
-use clap::Parser;
-use diesel::connection::SimpleConnection;
-use diesel::r2d2::{ConnectionManager, Pool};
-use diesel::SqliteConnection;
-use diesel_migrations::MigrationHarness;
-use nostr::key::XOnlyPublicKey;
+use axum::routing::{get, post};
+use axum::{Extension, Router};
+use diesel::r2d2::Pool;
 use tokio::sync::watch;
-use tokio::sync::watch::Sender;
 use tower_http::cors::{Any, CorsLayer};
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-use crate::config::*;
-use crate::models::service_nwc::ServiceNwc;
-use crate::models::user_nwc::UserNwc;
-use crate::models::MIGRATIONS;
-use crate::routes::*;
-
-mod config;
-mod models;
-mod routes;
-mod subscriber;
-
-#[derive(Clone)]
-pub struct State {
-    pubkeys: Arc<Mutex<Sender<Vec<XOnlyPublicKey>>>>,
-    db_pool: Pool<ConnectionManager<SqliteConnection>>,
-}
+use nwc_proxy::config::*;
+use nwc_proxy::encryption::MasterKey;
+use nwc_proxy::models::service_nwc::ServiceNwc;
+use nwc_proxy::models::user_nwc::UserNwc;
+use nwc_proxy::models::DbConnectionManager;
+use nwc_proxy::routes::*;
+use nwc_proxy::{admin, identity, lnurl, models, subscriber, zaps, ConnectionOptions, State};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let config: Config = Config::parse();
+    let mut config = Config::load()?;
+    init_logging(&config)?;
 
     // Create the datadir if it doesn't exist
     let path = PathBuf::from(&config.data_dir);
     std::fs::create_dir_all(path.clone())?;
 
-    let db_path = {
-        let mut path = path.clone();
-        path.push("db.sqlite");
-        path
+    let database_url = match config.database_url.clone() {
+        Some(url) => url,
+        None => {
+            let mut path = path.clone();
+            path.push("db.sqlite");
+            path.to_str().unwrap().to_string()
+        }
     };
 
     // DB management
-    let manager = ConnectionManager::<SqliteConnection>::new(db_path.to_str().unwrap());
+    let manager = DbConnectionManager::new(database_url);
     let db_pool = Pool::builder()
-        .max_size(16)
+        .max_size(config.db_pool_size)
         .connection_customizer(Box::new(ConnectionOptions {
             enable_wal: true,
             enable_foreign_keys: true,
-            busy_timeout: Some(Duration::from_secs(30)),
+            busy_timeout: Some(Duration::from_secs(config.db_timeout)),
         }))
         .test_on_check_out(true)
         .build(manager)
         .expect("Could not build connection pool");
 
-    let start = {
+    // don't keep the master key around in the config once loaded
+    let master_key = MasterKey::load_or_create(&path, config.master_key.take().as_deref())?;
+
+    {
         let connection = &mut db_pool.get()?;
         // run migrations if needed
-        connection
-            .run_pending_migrations(MIGRATIONS)
-            .expect("migrations could not run");
+        connection.run_migrations()?;
+
+        let migrated = models::encrypt_existing_secrets(connection, &master_key)?;
+        if migrated > 0 {
+            info!("Encrypted {migrated} plaintext NWC secrets");
+        }
+    }
+
+    // admin commands operate on the database directly and exit
+    match config.command.take() {
+        None | Some(Command::Serve) => {}
+        Some(command) => return admin::run(command, &mut *db_pool.get()?, &master_key),
+    }
+
+    let keys = identity::load_or_create_identity(&path)?;
+    info!("Proxy pubkey: {}", keys.public_key());
+
+    let start = {
+        let connection = &mut db_pool.get()?;
 
         let service_keys = ServiceNwc::get_all_keys(connection)?;
         let user_keys = UserNwc::get_all_keys(connection)?;
@@ -77,74 +86,129 @@ async fn main() -> anyhow::Result<()> {
 
     let (tx, rx) = watch::channel(start);
 
-    let tx_shared = Arc::new(Mutex::new(tx));
-
-    let state = State {
-        db_pool,
-        pubkeys: tx_shared.clone(),
-    };
+    let state = State::new(db_pool, master_key, config, keys, tx)?;
 
-    let addr: std::net::SocketAddr = format!("{}:{}", config.bind, config.port)
+    let addr: std::net::SocketAddr = format!("{}:{}", state.config().bind, state.config().port)
         .parse()
         .expect("Failed to parse bind/port for webserver");
 
-    println!("Webserver running on http://{}", addr);
+    let cors = state.config().cors.layer()?;
+    if state.config().cors.allow_any_origin {
+        warn!("CORS allows any origin, any website can call the API");
+    }
 
-    let server_router = Router::new()
+    let api_router = Router::new()
         .route("/set-user-nwc", post(set_user_nwc))
         .route("/get-service-nwc", post(get_service_nwc))
-        .fallback(fallback)
-        .layer(Extension(state.clone()))
+        .route("/update-service-nwc", post(update_service_nwc))
+        .route("/register-username", post(register_username))
+        .route("/metrics", get(metrics))
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/relays", get(relays))
+        .layer(cors);
+
+    // wallets fetch these from anywhere, including web wallets
+    let lnurl_router = Router::new()
+        .route("/.well-known/lnurlp/:username", get(lnurl::pay_request))
+        .route("/lnurlp/:username/callback", get(lnurl::callback))
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
-                .allow_headers(vec![http::header::CONTENT_TYPE])
-                .allow_methods([Method::GET, Method::POST]),
+                .allow_methods([Method::GET]),
         );
 
-    let server = axum::Server::bind(&addr).serve(server_router.into_make_service());
+    let server_router = api_router
+        .merge(lnurl_router)
+        .fallback(fallback)
+        .layer(Extension(state.clone()));
+    if let Some(public_url) = &state.config().public_url {
+        info!("Lightning addresses enabled at {public_url}");
+    }
+
+    let tls = match state.config().tls.clone() {
+        Some(tls) => {
+            let rustls = tls.load().await?;
+            tokio::spawn(tls.watch(rustls.clone()));
+            Some(rustls)
+        }
+        None => None,
+    };
+
+    tokio::spawn(subscriber::purge_expired_keys(state.clone()));
+    tokio::spawn(subscriber::expire_pending_payments(state.clone()));
+    if state.config().public_url.is_some() {
+        tokio::spawn(zaps::watch_zaps(state.clone()));
+    }
 
-    tokio::spawn(subscriber::start_subscription(state.db_pool, rx));
+    let (stop_subscriber, shutdown_rx) = watch::channel(false);
+    let subscriber = tokio::spawn(async move {
+        if let Err(e) = subscriber::start_subscription(state, rx, shutdown_rx).await {
+            error!("Subscriber stopped: {e}");
+        }
+    });
 
-    let graceful = server.with_graceful_shutdown(async {
+    let shutdown = async {
         tokio::signal::ctrl_c()
             .await
             .expect("failed to create Ctrl+C shutdown signal");
-    });
+    };
 
     // Await the server to receive the shutdown signal
-    if let Err(e) = graceful.await {
-        eprintln!("shutdown error: {}", e);
+    let result = match tls {
+        Some(rustls) => {
+            info!("Webserver running on https://{}", addr);
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown.await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            axum_server::bind_rustls(addr, rustls)
+                .handle(handle)
+                .serve(server_router.into_make_service())
+                .await
+                .map_err(anyhow::Error::from)
+        }
+        None => {
+            info!("Webserver running on http://{}", addr);
+            axum::Server::bind(&addr)
+                .serve(server_router.into_make_service())
+                .with_graceful_shutdown(shutdown)
+                .await
+                .map_err(anyhow::Error::from)
+        }
+    };
+    if let Err(e) = result {
+        error!("shutdown error: {}", e);
     }
 
-    Ok(())
-}
+    // the server no longer takes requests, let payments already forwarded finish
+    let _ = stop_subscriber.send(true);
+    if let Err(e) = subscriber.await {
+        error!("Subscriber task failed: {e}");
+    }
 
-#[derive(Debug)]
-pub struct ConnectionOptions {
-    pub enable_wal: bool,
-    pub enable_foreign_keys: bool,
-    pub busy_timeout: Option<Duration>,
+    Ok(())
 }
 
-impl diesel::r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error>
-    for ConnectionOptions
-{
-    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
-        (|| {
-            if self.enable_wal {
-                conn.batch_execute("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
-            }
-            if self.enable_foreign_keys {
-                conn.batch_execute("PRAGMA foreign_keys = ON;")?;
-            }
-            if let Some(d) = self.busy_timeout {
-                conn.batch_execute(&format!("PRAGMA busy_timeout = {};", d.as_millis()))?;
-            }
-            Ok(())
-        })()
-        .map_err(diesel::r2d2::Error::QueryError)
+/// Sets up the global tracing subscriber, `RUST_LOG` takes precedence over the configured level
+fn init_logging(config: &Config) -> anyhow::Result<()> {
+    let filter =
+        EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(&config.log_level))?;
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    if config.log_json {
+        builder.json().init();
+    } else {
+        builder.init();
     }
+
+    Ok(())
 }
 
 async fn fallback(uri: Uri) -> (StatusCode, String) {
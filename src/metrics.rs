@@ -0,0 +1,164 @@
+use nostr_sdk::RelayStatus;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+const RELAY_STATUSES: [&str; 6] = [
+    "initialized",
+    "connected",
+    "connecting",
+    "disconnected",
+    "stopped",
+    "terminated",
+];
+
+/// Prometheus metrics for the proxy, cheap to clone.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Requests received from services, by method
+    pub requests_received: IntCounterVec,
+    /// Requests forwarded to the user's wallet, by method
+    pub requests_forwarded: IntCounterVec,
+    /// Requests that were not forwarded, by reason
+    pub requests_rejected: IntCounterVec,
+    /// Time between forwarding a request and receiving the wallet's response
+    pub upstream_latency: HistogramVec,
+    /// Event handlers that hit the timeout, by event kind
+    pub timeouts: IntCounterVec,
+    /// Events that had to wait for a free worker, by event kind
+    pub events_delayed: IntCounterVec,
+    /// Events missed because the subscriber fell behind the relays
+    pub events_dropped: IntCounter,
+    /// Attempts to publish an event that a relay did not accept, by relay
+    pub publish_failures: IntCounterVec,
+    /// Current connection state of each relay
+    pub relay_status: IntGaugeVec,
+    /// Reconnects to relays that went down, by relay
+    pub relay_reconnects: IntCounterVec,
+    /// Number of keys the subscriber is watching
+    pub active_keys: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("nwc_proxy".to_string()), None)?;
+
+        let requests_received = IntCounterVec::new(
+            Opts::new("requests_received_total", "Requests received from services"),
+            &["method"],
+        )?;
+        let requests_forwarded = IntCounterVec::new(
+            Opts::new(
+                "requests_forwarded_total",
+                "Requests forwarded to user wallets",
+            ),
+            &["method"],
+        )?;
+        let requests_rejected = IntCounterVec::new(
+            Opts::new(
+                "requests_rejected_total",
+                "Requests that were not forwarded",
+            ),
+            &["reason"],
+        )?;
+        let upstream_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_response_seconds",
+                "Time for a user wallet to respond to a forwarded request",
+            )
+            .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0]),
+            &["method"],
+        )?;
+        let timeouts = IntCounterVec::new(
+            Opts::new("timeouts_total", "Event handlers that timed out"),
+            &["kind"],
+        )?;
+        let events_delayed = IntCounterVec::new(
+            Opts::new(
+                "events_delayed_total",
+                "Events that had to wait for a free worker",
+            ),
+            &["kind"],
+        )?;
+        let events_dropped = IntCounter::new(
+            "events_dropped_total",
+            "Events missed because the subscriber fell behind the relays",
+        )?;
+        let publish_failures = IntCounterVec::new(
+            Opts::new(
+                "publish_failures_total",
+                "Attempts to publish an event that a relay did not accept",
+            ),
+            &["relay"],
+        )?;
+        let relay_status = IntGaugeVec::new(
+            Opts::new("relay_status", "Connection state of each relay"),
+            &["relay", "status"],
+        )?;
+        let relay_reconnects = IntCounterVec::new(
+            Opts::new(
+                "relay_reconnects_total",
+                "Reconnects to relays that went down",
+            ),
+            &["relay"],
+        )?;
+        let active_keys = IntGauge::new("active_keys", "Number of keys being watched")?;
+
+        registry.register(Box::new(requests_received.clone()))?;
+        registry.register(Box::new(requests_forwarded.clone()))?;
+        registry.register(Box::new(requests_rejected.clone()))?;
+        registry.register(Box::new(upstream_latency.clone()))?;
+        registry.register(Box::new(timeouts.clone()))?;
+        registry.register(Box::new(events_delayed.clone()))?;
+        registry.register(Box::new(events_dropped.clone()))?;
+        registry.register(Box::new(publish_failures.clone()))?;
+        registry.register(Box::new(relay_status.clone()))?;
+        registry.register(Box::new(relay_reconnects.clone()))?;
+        registry.register(Box::new(active_keys.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests_received,
+            requests_forwarded,
+            requests_rejected,
+            upstream_latency,
+            timeouts,
+            events_delayed,
+            events_dropped,
+            publish_failures,
+            relay_status,
+            relay_reconnects,
+            active_keys,
+        })
+    }
+
+    pub fn set_relay_status(&self, relay: &str, status: RelayStatus) {
+        let current = relay_status_name(status);
+        for status in RELAY_STATUSES {
+            let value = i64::from(status == current);
+            self.relay_status
+                .with_label_values(&[relay, status])
+                .set(value);
+        }
+    }
+
+    /// Renders all metrics in the prometheus text format
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+fn relay_status_name(status: RelayStatus) -> &'static str {
+    match status {
+        RelayStatus::Initialized => "initialized",
+        RelayStatus::Connected => "connected",
+        RelayStatus::Connecting => "connecting",
+        RelayStatus::Disconnected => "disconnected",
+        RelayStatus::Stopped => "stopped",
+        RelayStatus::Terminated => "terminated",
+    }
+}
@@ -0,0 +1,81 @@
+//! An in-process relay and wallet, so tests can run requests through the proxy end to end
+
+pub mod relay;
+pub mod wallet;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use diesel::r2d2::Pool;
+use nostr::key::XOnlyPublicKey;
+use tokio::sync::watch;
+
+use crate::config::{Config, CorsConfig, PolicyConfig};
+use crate::encryption::MasterKey;
+use crate::metrics::Metrics;
+use crate::models::service_nwc::ServiceNwc;
+use crate::models::user_nwc::UserNwc;
+use crate::models::{DbConnection, DbConnectionManager};
+use crate::subscriber::SubscriberStatus;
+use crate::{ConnectionOptions, State};
+
+/// Proxy state using the given database and relay, along with the keys the subscriber
+/// should watch, loaded from the database like on startup
+pub fn test_state(
+    database_url: &str,
+    relay_url: &str,
+    master_key: MasterKey,
+) -> anyhow::Result<(State, watch::Receiver<Vec<XOnlyPublicKey>>)> {
+    let db_pool = Pool::builder()
+        .max_size(4)
+        .connection_customizer(Box::new(ConnectionOptions {
+            enable_wal: false,
+            enable_foreign_keys: true,
+            busy_timeout: Some(Duration::from_secs(5)),
+        }))
+        .build(DbConnectionManager::new(database_url))?;
+
+    let keys = {
+        let conn: &mut DbConnection = &mut *db_pool.get()?;
+        let mut keys = ServiceNwc::get_all_keys(conn)?;
+        keys.extend(UserNwc::get_all_keys(conn)?);
+        keys
+    };
+    let (tx, rx) = watch::channel(keys);
+
+    let config = Config {
+        data_dir: ".".to_string(),
+        bind: "127.0.0.1".to_string(),
+        port: 0,
+        tls: None,
+        database_url: Some(database_url.to_string()),
+        master_key: None,
+        log_level: "info".to_string(),
+        log_json: false,
+        public_url: None,
+        service_relay: relay_url.to_string(),
+        relays: vec![],
+        db_pool_size: 4,
+        db_timeout: 5,
+        request_timeout: 10,
+        workers: 3,
+        queue_size: 16,
+        cors: CorsConfig::default(),
+        policy: PolicyConfig::default(),
+        command: None,
+    };
+
+    let identity = nostr::Keys::generate();
+    let state = State {
+        pubkeys: Arc::new(Mutex::new(tx)),
+        db_pool,
+        master_key,
+        metrics: Metrics::new()?,
+        subscriber: SubscriberStatus::default(),
+        config: Arc::new(config),
+        identity: identity.clone(),
+        zap_client: crate::zaps::receipt_client(&identity),
+    };
+
+    Ok((state, rx))
+}
@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use nostr::key::XOnlyPublicKey;
+use nostr::{ClientMessage, Event, EventId, Filter, RelayMessage, SubscriptionId, TagKind};
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, oneshot};
+
+/// Subscriptions of every connection, by connection and subscription id
+type Subscriptions = Arc<Mutex<HashMap<(usize, SubscriptionId), Vec<Filter>>>>;
+
+/// A nostr relay that keeps every event in memory, ephemeral ones included, so tests
+/// can look at what was published
+pub struct MockRelay {
+    url: String,
+    state: RelayState,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Clone)]
+struct RelayState {
+    events: Arc<Mutex<Vec<Event>>>,
+    new_events: broadcast::Sender<Event>,
+    subscriptions: Subscriptions,
+    next_connection: Arc<AtomicUsize>,
+}
+
+impl MockRelay {
+    /// Starts the relay on a random local port
+    pub async fn start() -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("ws://{}", listener.local_addr()?);
+
+        let state = RelayState {
+            events: Arc::default(),
+            new_events: broadcast::channel(1024).0,
+            subscriptions: Arc::default(),
+            next_connection: Arc::default(),
+        };
+        let app = Router::new()
+            .route("/", get(accept))
+            .layer(Extension(state.clone()));
+
+        let (shutdown, rx) = oneshot::channel::<()>();
+        let server = axum::Server::from_tcp(listener)?
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(async {
+                rx.await.ok();
+            });
+        tokio::spawn(server);
+
+        Ok(Self {
+            url,
+            state,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Every event published so far, oldest first
+    pub fn events(&self) -> Vec<Event> {
+        self.state.events.lock().unwrap().clone()
+    }
+
+    /// Publishes an event as if a client sent it
+    pub fn publish(&self, event: Event) {
+        self.state.store(event);
+    }
+
+    /// Waits for an event matching the filter, including ones already published
+    pub async fn wait_for(&self, filter: Filter, timeout: Duration) -> anyhow::Result<Event> {
+        let mut new_events = self.state.new_events.subscribe();
+        if let Some(event) = self.events().into_iter().find(|e| matches(&filter, e)) {
+            return Ok(event);
+        }
+
+        tokio::time::timeout(timeout, async {
+            loop {
+                match new_events.recv().await {
+                    Ok(event) if matches(&filter, &event) => return Ok(event),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(e) => return Err(anyhow!(e)),
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for event matching {}", filter.as_json()))?
+    }
+
+    /// Waits until some client is subscribed to events tagging the pubkey
+    pub async fn wait_for_subscriber(
+        &self,
+        pubkey: XOnlyPublicKey,
+        timeout: Duration,
+    ) -> anyhow::Result<()> {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let subscribed = self
+                    .state
+                    .subscriptions
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .flatten()
+                    .any(|filter| filter.pubkeys.contains(&pubkey));
+                if subscribed {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for a subscriber to {pubkey}"))
+    }
+}
+
+impl Drop for MockRelay {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+impl RelayState {
+    fn store(&self, event: Event) {
+        self.events.lock().unwrap().push(event.clone());
+        let _ = self.new_events.send(event);
+    }
+
+    fn handle(&self, connection: usize, msg: &str) -> Vec<RelayMessage> {
+        let msg = match ClientMessage::from_json(msg) {
+            Ok(msg) => msg,
+            // events that fail to verify are refused with an OK message, like a real relay
+            Err(e) => match rejected_event_id(msg) {
+                Some(id) => return vec![RelayMessage::new_ok(id, false, format!("invalid: {e}"))],
+                None => return vec![RelayMessage::new_notice(format!("invalid: {e}"))],
+            },
+        };
+
+        match msg {
+            ClientMessage::Event(event) | ClientMessage::Auth(event) => {
+                if event.verify().is_err() {
+                    return vec![RelayMessage::new_ok(event.id, false, "invalid: bad event")];
+                }
+                let id = event.id;
+                self.store(*event);
+                vec![RelayMessage::new_ok(id, true, "")]
+            }
+            ClientMessage::Req {
+                subscription_id,
+                filters,
+            } => {
+                let mut replies: Vec<RelayMessage> = self
+                    .events
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|event| filters.iter().any(|f| matches(f, event)))
+                    .map(|event| RelayMessage::new_event(subscription_id.clone(), event.clone()))
+                    .collect();
+                replies.push(RelayMessage::new_eose(subscription_id.clone()));
+                self.subscriptions
+                    .lock()
+                    .unwrap()
+                    .insert((connection, subscription_id), filters);
+                replies
+            }
+            ClientMessage::Count {
+                subscription_id,
+                filters,
+            } => {
+                let count = self
+                    .events
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|event| filters.iter().any(|f| matches(f, event)))
+                    .count();
+                vec![RelayMessage::new_count(subscription_id, count)]
+            }
+            ClientMessage::Close(subscription_id) => {
+                self.subscriptions
+                    .lock()
+                    .unwrap()
+                    .remove(&(connection, subscription_id));
+                vec![]
+            }
+        }
+    }
+
+    async fn serve(self, mut socket: WebSocket) {
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        let mut new_events = self.new_events.subscribe();
+        loop {
+            let replies = tokio::select! {
+                msg = socket.recv() => match msg {
+                    Some(Ok(Message::Text(text))) => self.handle(connection, &text),
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                },
+                event = new_events.recv() => match event {
+                    Ok(event) => self
+                        .subscriptions
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|((conn, _), filters)| {
+                            *conn == connection && filters.iter().any(|f| matches(f, &event))
+                        })
+                        .map(|((_, id), _)| RelayMessage::new_event(id.clone(), event.clone()))
+                        .collect(),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            };
+
+            for reply in replies {
+                if socket.send(Message::Text(reply.as_json())).await.is_err() {
+                    break;
+                }
+            }
+        }
+
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .retain(|(conn, _), _| *conn != connection);
+    }
+}
+
+/// Serves websocket connections, and the NIP-11 information document to plain requests
+async fn accept(ws: Option<WebSocketUpgrade>, Extension(state): Extension<RelayState>) -> Response {
+    match ws {
+        Some(ws) => ws.on_upgrade(move |socket| state.serve(socket)),
+        None => Json(json!({ "name": "mock relay", "supported_nips": [1, 11] })).into_response(),
+    }
+}
+
+/// Id of the event in an EVENT message that could not be parsed
+fn rejected_event_id(msg: &str) -> Option<EventId> {
+    let msg: Value = serde_json::from_str(msg).ok()?;
+    if msg.get(0)?.as_str()? != "EVENT" {
+        return None;
+    }
+    EventId::from_hex(msg.get(1)?.get("id")?.as_str()?).ok()
+}
+
+/// NIP-01 filter matching, for the fields the proxy uses
+fn matches(filter: &Filter, event: &Event) -> bool {
+    let tag_values = |kind: TagKind| -> Vec<String> {
+        event
+            .tags
+            .iter()
+            .filter(|tag| tag.kind() == kind)
+            .filter_map(|tag| tag.as_vec().get(1).cloned())
+            .collect()
+    };
+    let id = event.id.to_hex();
+    let author = event.pubkey.to_string();
+
+    (filter.ids.is_empty() || filter.ids.iter().any(|prefix| id.starts_with(prefix)))
+        && (filter.authors.is_empty()
+            || filter
+                .authors
+                .iter()
+                .any(|prefix| author.starts_with(prefix)))
+        && (filter.kinds.is_empty() || filter.kinds.contains(&event.kind))
+        && (filter.events.is_empty()
+            || tag_values(TagKind::E)
+                .iter()
+                .any(|id| filter.events.iter().any(|e| e.to_hex() == *id)))
+        && (filter.pubkeys.is_empty()
+            || tag_values(TagKind::P)
+                .iter()
+                .any(|pk| filter.pubkeys.iter().any(|p| p.to_string() == *pk)))
+        && (filter.identifiers.is_empty()
+            || tag_values(TagKind::D)
+                .iter()
+                .any(|d| filter.identifiers.contains(d)))
+        && filter.since.is_none_or(|since| event.created_at >= since)
+        && filter.until.is_none_or(|until| event.created_at <= until)
+}
@@ -0,0 +1,314 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
+use lightning::ln::PaymentSecret;
+use lightning_invoice::{Bolt11Invoice, Currency, InvoiceBuilder};
+use nostr::nips::nip47::{ErrorCode, NostrWalletConnectURI};
+use nostr::prelude::{decrypt, encrypt};
+use nostr::{Event, EventBuilder, Filter, Keys, Kind, Tag, Timestamp, Url};
+use nostr_sdk::{Client, Options, RelayPoolNotification};
+use serde_json::json;
+
+use crate::nwc::{MultiPayInvoiceParams, NwcMethod, NwcRequest, NwcResponse};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One response event the wallet sends for a request
+#[derive(Debug, Clone)]
+pub struct WalletReply {
+    pub response: NwcResponse,
+    /// Identifies which payment of a multi payment request this answers
+    pub d_tag: Option<String>,
+    /// How long to wait after the previous reply before sending this one
+    pub delay: Duration,
+}
+
+impl WalletReply {
+    pub fn new(response: NwcResponse) -> Self {
+        Self {
+            response,
+            d_tag: None,
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+/// Decides how the wallet answers each request, it may send several responses or none
+pub type Script = Arc<dyn Fn(&NwcRequest) -> Vec<WalletReply> + Send + Sync>;
+
+/// How a wallet built with [`scripted`] answers
+#[derive(Debug, Clone)]
+pub struct Behaviour {
+    /// Methods answered with `error_code` instead of succeeding
+    pub fail: Vec<NwcMethod>,
+    pub error_code: ErrorCode,
+    /// Methods that are never answered, so requests time out
+    pub ignore: Vec<NwcMethod>,
+    pub balance_msats: u64,
+}
+
+impl Default for Behaviour {
+    fn default() -> Self {
+        Self {
+            fail: vec![],
+            error_code: ErrorCode::Internal,
+            ignore: vec![],
+            balance_msats: 100_000_000,
+        }
+    }
+}
+
+/// A NIP-47 wallet service on a relay that answers requests from a script
+pub struct MockWallet {
+    keys: Keys,
+    /// Secret of the connection given out in the NWC uri
+    secret: SecretKey,
+    relay_url: Url,
+    requests: Arc<Mutex<Vec<NwcRequest>>>,
+    client: Client,
+}
+
+impl MockWallet {
+    /// Connects to the relay, publishes the wallet's info event listing `methods` and starts
+    /// answering requests, each after waiting `latency`
+    pub async fn start(
+        relay_url: &str,
+        methods: &[NwcMethod],
+        script: Script,
+        latency: Duration,
+    ) -> anyhow::Result<Self> {
+        let keys = Keys::generate();
+        let secret = Keys::generate().secret_key()?;
+        let relay_url = Url::parse(relay_url)?;
+
+        let opts = Options::new()
+            .wait_for_connection(true)
+            .wait_for_subscription(true);
+        let client = Client::with_opts(&keys, opts);
+        client.add_relay(relay_url.as_str(), None).await?;
+
+        let methods: Vec<&str> = methods.iter().map(|m| m.as_str()).collect();
+        let info =
+            EventBuilder::new(Kind::WalletConnectInfo, methods.join(" "), &[]).to_event(&keys)?;
+        let filter = Filter::new()
+            .kind(Kind::WalletConnectRequest)
+            .pubkey(keys.public_key())
+            .since(Timestamp::now());
+        tokio::time::timeout(CONNECT_TIMEOUT, async {
+            client.connect().await;
+            client.send_event(info).await?;
+            client.subscribe(vec![filter]).await;
+            Ok::<_, anyhow::Error>(())
+        })
+        .await
+        .map_err(|_| anyhow!("Could not connect to {relay_url}"))??;
+
+        let requests: Arc<Mutex<Vec<NwcRequest>>> = Arc::default();
+        let connection_key = Keys::new(secret).public_key();
+        let mut notifications = client.notifications();
+        tokio::spawn({
+            let client = client.clone();
+            let keys = keys.clone();
+            let requests = requests.clone();
+            async move {
+                while let Ok(notification) = notifications.recv().await {
+                    let RelayPoolNotification::Event(_, event) = notification else {
+                        continue;
+                    };
+                    if event.kind != Kind::WalletConnectRequest || event.pubkey != connection_key {
+                        continue;
+                    }
+                    tokio::spawn({
+                        let client = client.clone();
+                        let keys = keys.clone();
+                        let requests = requests.clone();
+                        let script = script.clone();
+                        async move {
+                            tokio::time::sleep(latency).await;
+                            if let Err(e) = answer(&client, &keys, &requests, &script, event).await
+                            {
+                                tracing::warn!("Mock wallet could not answer request: {e}");
+                            }
+                        }
+                    });
+                }
+            }
+        });
+
+        Ok(Self {
+            keys,
+            secret,
+            relay_url,
+            requests,
+            client,
+        })
+    }
+
+    /// The uri to give the proxy for this wallet
+    pub fn nwc_uri(&self) -> NostrWalletConnectURI {
+        NostrWalletConnectURI {
+            public_key: self.keys.public_key(),
+            secret: self.secret,
+            relay_url: self.relay_url.clone(),
+            lud16: None,
+        }
+    }
+
+    /// Requests received so far, oldest first
+    pub fn requests(&self) -> Vec<NwcRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub async fn stop(self) -> anyhow::Result<()> {
+        self.client.disconnect().await?;
+        Ok(())
+    }
+}
+
+async fn answer(
+    client: &Client,
+    keys: &Keys,
+    requests: &Mutex<Vec<NwcRequest>>,
+    script: &Script,
+    event: Event,
+) -> anyhow::Result<()> {
+    let decrypted = decrypt(&keys.secret_key()?, &event.pubkey, &event.content)?;
+    let req = NwcRequest::from_json(decrypted)?;
+    requests.lock().unwrap().push(req.clone());
+
+    for reply in script(&req) {
+        tokio::time::sleep(reply.delay).await;
+        let encrypted = encrypt(&keys.secret_key()?, &event.pubkey, reply.response.as_json())?;
+        let mut tags = vec![
+            Tag::PubKey(event.pubkey, None),
+            Tag::Event(event.id, None, None),
+        ];
+        if let Some(d_tag) = reply.d_tag {
+            tags.push(Tag::Identifier(d_tag));
+        }
+        let response =
+            EventBuilder::new(Kind::WalletConnectResponse, encrypted, &tags).to_event(keys)?;
+        client.send_event(response).await?;
+    }
+
+    Ok(())
+}
+
+/// Answers every request successfully, like a wallet with plenty of funds
+pub fn pay_everything() -> Script {
+    scripted(Behaviour::default())
+}
+
+/// Answers requests as the behaviour says, successful payments all return the same preimage
+pub fn scripted(behaviour: Behaviour) -> Script {
+    Arc::new(move |req| {
+        let method = match NwcMethod::from_str(&req.method) {
+            Ok(method) => method,
+            Err(_) => {
+                let response =
+                    NwcResponse::error(&req.method, ErrorCode::NotImplemented, "Unknown method");
+                return vec![WalletReply::new(response)];
+            }
+        };
+        if behaviour.ignore.contains(&method) {
+            return vec![];
+        }
+        if behaviour.fail.contains(&method) {
+            let code = behaviour.error_code.clone();
+            let response = NwcResponse::error(method.as_str(), code, "Mock wallet failure");
+            return vec![WalletReply::new(response)];
+        }
+        let preimage = || json!({ "preimage": "00".repeat(32) });
+
+        match method {
+            NwcMethod::PayInvoice | NwcMethod::PayKeysend => {
+                vec![WalletReply::new(NwcResponse::success(method, preimage()))]
+            }
+            NwcMethod::MultiPayInvoice => {
+                let invoices = req
+                    .params::<MultiPayInvoiceParams>()
+                    .map(|params| params.invoices)
+                    .unwrap_or_default();
+                invoices
+                    .into_iter()
+                    .map(|item| {
+                        let d_tag = item.id.or_else(|| {
+                            Bolt11Invoice::from_str(&item.invoice)
+                                .ok()
+                                .map(|invoice| invoice.payment_hash().to_hex())
+                        });
+                        WalletReply {
+                            response: NwcResponse::success(method, preimage()),
+                            d_tag,
+                            delay: Duration::ZERO,
+                        }
+                    })
+                    .collect()
+            }
+            NwcMethod::MakeInvoice => {
+                let amount = req.params["amount"].as_u64().unwrap_or_default();
+                let description_hash = req.params["description_hash"]
+                    .as_str()
+                    .and_then(|hash| sha256::Hash::from_str(hash).ok());
+                let result = match create_invoice_with_hash(amount, description_hash) {
+                    Ok(invoice) => NwcResponse::success(
+                        method,
+                        json!({
+                            "type": "incoming",
+                            "invoice": invoice.to_string(),
+                            "payment_hash": invoice.payment_hash().to_hex(),
+                            "amount": amount,
+                        }),
+                    ),
+                    Err(e) => {
+                        NwcResponse::error(method.as_str(), ErrorCode::Internal, e.to_string())
+                    }
+                };
+                vec![WalletReply::new(result)]
+            }
+            NwcMethod::GetBalance => {
+                let result = json!({ "balance": behaviour.balance_msats });
+                vec![WalletReply::new(NwcResponse::success(method, result))]
+            }
+            NwcMethod::LookupInvoice | NwcMethod::ListTransactions => {
+                let response =
+                    NwcResponse::error(method.as_str(), ErrorCode::NotImplemented, "Not supported");
+                vec![WalletReply::new(response)]
+            }
+        }
+    })
+}
+
+/// A mainnet invoice for the amount signed by a random node, it can't actually be paid
+pub fn create_invoice(amount_msats: u64) -> anyhow::Result<Bolt11Invoice> {
+    create_invoice_with_hash(amount_msats, None)
+}
+
+/// Like [`create_invoice`], committing to `description_hash` instead of a description if given
+pub fn create_invoice_with_hash(
+    amount_msats: u64,
+    description_hash: Option<sha256::Hash>,
+) -> anyhow::Result<Bolt11Invoice> {
+    let node_key = Keys::generate().secret_key()?;
+    let preimage = Keys::generate().secret_key()?.secret_bytes();
+
+    let builder = InvoiceBuilder::new(Currency::Bitcoin);
+    let builder = match description_hash {
+        Some(description_hash) => builder.description_hash(description_hash),
+        None => builder.description("mock wallet".to_string()),
+    };
+    builder
+        .payment_hash(sha256::Hash::hash(&preimage))
+        .payment_secret(PaymentSecret([42; 32]))
+        .current_timestamp()
+        .expiry_time(Duration::from_secs(3600))
+        .min_final_cltv_expiry_delta(144)
+        .amount_milli_satoshis(amount_msats)
+        .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &node_key))
+        .map_err(|e| anyhow!("Could not create invoice: {e}"))
+}
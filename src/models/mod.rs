@@ -1,29 +1,125 @@
-use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+use diesel::r2d2::{ManageConnection, Pool};
+use diesel::{Connection, PgConnection, SqliteConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
+use crate::encryption::MasterKey;
+use crate::models::service_nwc::ServiceNwc;
+use crate::models::user_nwc::UserNwc;
+
+pub mod payment;
 pub mod schema;
 pub mod service_nwc;
 pub mod user;
 pub mod user_nwc;
+pub mod zap;
+
+pub const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/sqlite");
+pub const POSTGRES_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/postgres");
+
+/// A connection to either of the supported database backends
+#[derive(diesel::MultiConnection)]
+pub enum DbConnection {
+    Postgres(PgConnection),
+    Sqlite(SqliteConnection),
+}
+
+pub type DbPool = Pool<DbConnectionManager>;
 
-pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+pub fn is_postgres_url(database_url: &str) -> bool {
+    database_url.starts_with("postgres://") || database_url.starts_with("postgresql://")
+}
+
+impl DbConnection {
+    /// Connects to the backend matching the url, postgres urls use Postgres
+    /// and everything else is treated as a SQLite database
+    pub fn establish_url(database_url: &str) -> diesel::ConnectionResult<Self> {
+        if is_postgres_url(database_url) {
+            PgConnection::establish(database_url).map(DbConnection::Postgres)
+        } else {
+            SqliteConnection::establish(database_url).map(DbConnection::Sqlite)
+        }
+    }
+
+    pub fn run_migrations(&mut self) -> anyhow::Result<()> {
+        let result = match self {
+            DbConnection::Postgres(conn) => conn.run_pending_migrations(POSTGRES_MIGRATIONS),
+            DbConnection::Sqlite(conn) => conn.run_pending_migrations(SQLITE_MIGRATIONS),
+        };
+        result.map_err(|e| anyhow::anyhow!("migrations could not run: {e}"))?;
+
+        Ok(())
+    }
+}
+
+/// r2d2 connection manager that picks the backend from the url instead of
+/// trying every backend until one connects
+#[derive(Debug, Clone)]
+pub struct DbConnectionManager {
+    database_url: String,
+}
+
+impl DbConnectionManager {
+    pub fn new(database_url: impl Into<String>) -> Self {
+        Self {
+            database_url: database_url.into(),
+        }
+    }
+}
+
+impl ManageConnection for DbConnectionManager {
+    type Connection = DbConnection;
+    type Error = diesel::r2d2::Error;
+
+    fn connect(&self) -> Result<DbConnection, Self::Error> {
+        DbConnection::establish_url(&self.database_url)
+            .map_err(diesel::r2d2::Error::ConnectionError)
+    }
+
+    fn is_valid(&self, conn: &mut DbConnection) -> Result<(), Self::Error> {
+        use diesel::r2d2::R2D2Connection;
+        conn.ping().map_err(diesel::r2d2::Error::QueryError)
+    }
+
+    fn has_broken(&self, conn: &mut DbConnection) -> bool {
+        use diesel::r2d2::R2D2Connection;
+        std::thread::panicking() || conn.is_broken()
+    }
+}
+
+/// Encrypts NWC secrets written by older versions that stored them as plaintext.
+/// Safe to run on every start, already encrypted rows are skipped.
+pub fn encrypt_existing_secrets(
+    conn: &mut DbConnection,
+    master_key: &MasterKey,
+) -> Result<usize, diesel::result::Error> {
+    let user = UserNwc::encrypt_plaintext_keys(conn, master_key)?;
+    let service = ServiceNwc::encrypt_plaintext_keys(conn, master_key)?;
+    Ok(user + service)
+}
 
 #[cfg(test)]
-mod test {
-    use crate::models::service_nwc::ServiceNwc;
+pub(crate) mod test {
+    use crate::encryption::MasterKey;
+    use crate::models::payment::{Payment, PaymentFilter, PaymentStatus};
+    use crate::models::schema::{service_nwc, user_nwc};
+    use crate::models::service_nwc::{ServiceMetadata, ServiceNwc, DEFAULT_SERVICE_RELAY};
     use crate::models::user::*;
     use crate::models::user_nwc::UserNwc;
+    use crate::models::zap::Zap;
+    use crate::models::DbConnection;
     use bitcoin::hashes::hex::ToHex;
     use bitcoin::secp256k1::rand::Rng;
     use bitcoin::secp256k1::{rand, PublicKey};
-    use diesel::{Connection, SqliteConnection};
-    use diesel_migrations::MigrationHarness;
+    use diesel::prelude::*;
+    use nostr::key::XOnlyPublicKey;
     use nostr::nips::nip47::NostrWalletConnectURI;
+    use nostr::EventId;
     use std::str::FromStr;
 
     const PUB_KEY_STR: &str = "032e58afe51f9ed8ad3cc7897f634d881fdbe49a81564629ded8156bebd2ffd1af";
     const NWC_URI_STR: &str = "nostr+walletconnect://5fa11a95186e2bdc05e047d8573721b407aaa54e5c39f93b2811f176a65ac5f8?relay=wss%3A%2F%2Fnostr.mutinywallet.com%2F&secret=e0d196bf4af30401332085702d35ec0c0b6d6bcc43b76d05d9d9898b2c2c6d94";
 
-    fn gen_tmp_db_name() -> String {
+    pub(crate) fn gen_tmp_db_name() -> String {
         let rng = rand::thread_rng();
         let rand_string: String = rng
             .sample_iter(&rand::distributions::Alphanumeric)
@@ -33,17 +129,14 @@ mod test {
         format!("/tmp/nwc_proxy_{}.sqlite", rand_string)
     }
 
-    fn create_database(db_name: &str) -> SqliteConnection {
-        let mut connection = SqliteConnection::establish(db_name).unwrap();
-
-        connection
-            .run_pending_migrations(crate::models::MIGRATIONS)
-            .expect("migrations could not run");
+    pub(crate) fn create_database(db_name: &str) -> DbConnection {
+        let mut connection = DbConnection::establish_url(db_name).unwrap();
+        connection.run_migrations().unwrap();
 
         connection
     }
 
-    fn teardown_database(db_name: &str) {
+    pub(crate) fn teardown_database(db_name: &str) {
         std::fs::remove_file(db_name).unwrap();
     }
 
@@ -75,12 +168,124 @@ mod test {
         let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
         User::create(conn, pk).unwrap();
 
+        let master_key = MasterKey::generate().0;
         let nwc = NostrWalletConnectURI::from_str(NWC_URI_STR).unwrap();
-        let db = UserNwc::create(conn, nwc, pk).unwrap();
+        let db = UserNwc::create(conn, nwc.clone(), pk, &master_key).unwrap();
 
         let found = UserNwc::find_by_user(conn, &pk).unwrap();
         assert_eq!(found.len(), 1);
         assert_eq!(found[0], db);
+        assert_eq!(found[0].nwc_uri(&master_key).unwrap(), nwc);
+
+        // secret should not be stored in plaintext
+        let stored: String = user_nwc::table
+            .select(user_nwc::response_key)
+            .first(conn)
+            .unwrap();
+        assert!(!stored.contains(&nwc.secret.secret_bytes().to_hex()));
+
+        assert_eq!(found[0].relays(), vec![nwc.relay_url.clone()]);
+        let fallbacks = vec![nostr::Url::parse("wss://relay.example.com").unwrap()];
+        UserNwc::set_fallback_relays(conn, &nwc.public_key, &fallbacks).unwrap();
+        let found = UserNwc::find_by_user(conn, &pk).unwrap();
+        assert_eq!(found[0].fallback_relays(), fallbacks);
+        let fallback = &fallbacks[0];
+        assert_eq!(
+            found[0].relays(),
+            vec![nwc.relay_url.clone(), fallback.clone()]
+        );
+        let mut relays = UserNwc::get_relays(conn).unwrap();
+        relays.sort();
+        assert_eq!(
+            relays,
+            vec![nwc.relay_url.to_string(), fallback.to_string()]
+        );
+
+        teardown_database(&db_name);
+    }
+
+    #[test]
+    fn test_user_lud16() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
+        User::create(conn, pk).unwrap();
+        assert_eq!(User::lightning_address(conn, &pk).unwrap(), None);
+
+        // the wallet's lightning address is kept
+        let master_key = MasterKey::generate().0;
+        let mut nwc = NostrWalletConnectURI::from_str(NWC_URI_STR).unwrap();
+        nwc.lud16 = Some("wallet@example.com".to_string());
+        UserNwc::create(conn, nwc.clone(), pk, &master_key).unwrap();
+        let found = UserNwc::find_by_user(conn, &pk).unwrap();
+        assert_eq!(found[0].nwc_uri(&master_key).unwrap(), nwc);
+        assert_eq!(
+            User::lightning_address(conn, &pk).unwrap().as_deref(),
+            Some("wallet@example.com")
+        );
+
+        // one set for the user takes precedence
+        assert!(User::set_lud16(conn, &pk, Some("user@example.com")).unwrap());
+        let lud16 = User::lightning_address(conn, &pk).unwrap();
+        assert_eq!(lud16.as_deref(), Some("user@example.com"));
+
+        let service = ServiceNwc::generate(
+            pk,
+            "service".to_string(),
+            DEFAULT_SERVICE_RELAY,
+            &master_key,
+        );
+        let uri = service.nwc_uri(&master_key, lud16).unwrap().to_string();
+        assert!(uri.contains("lud16=user%40example.com"));
+
+        // clearing falls back to the wallet's
+        assert!(User::set_lud16(conn, &pk, None).unwrap());
+        assert_eq!(
+            User::lightning_address(conn, &pk).unwrap().as_deref(),
+            Some("wallet@example.com")
+        );
+
+        assert!(validate_lud16("satoshi@example.com").is_ok());
+        assert!(validate_lud16("satoshi").is_err());
+        assert!(validate_lud16("Satoshi@example.com").is_err());
+        assert!(validate_lud16("satoshi@localhost").is_err());
+        assert!(validate_lud16("satoshi@example..com").is_err());
+
+        teardown_database(&db_name);
+    }
+
+    #[test]
+    fn test_username() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
+        User::create(conn, pk).unwrap();
+        let other = PublicKey::from_str(
+            "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619",
+        )
+        .unwrap();
+        User::create(conn, other).unwrap();
+
+        assert!(User::set_username(conn, &pk, Some("satoshi")).unwrap());
+        let found = User::find_by_username(conn, "satoshi").unwrap().unwrap();
+        assert_eq!(found.pubkey(), pk);
+        assert_eq!(found.username(), Some("satoshi"));
+
+        // usernames are unique
+        assert!(User::set_username(conn, &other, Some("satoshi")).is_err());
+        assert!(User::set_username(conn, &other, Some("hal")).unwrap());
+
+        // released usernames can be taken
+        assert!(User::set_username(conn, &pk, None).unwrap());
+        assert!(User::find_by_username(conn, "satoshi").unwrap().is_none());
+        assert!(User::set_username(conn, &other, Some("satoshi")).unwrap());
+
+        assert!(validate_username("satoshi.n_1").is_ok());
+        assert!(validate_username("").is_err());
+        assert!(validate_username("Satoshi").is_err());
+        assert!(validate_username("sat oshi").is_err());
 
         teardown_database(&db_name);
     }
@@ -93,13 +298,374 @@ mod test {
         let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
         User::create(conn, pk).unwrap();
 
-        let db = ServiceNwc::generate(pk, "service".to_string());
+        let master_key = MasterKey::generate().0;
+        let db = ServiceNwc::generate(
+            pk,
+            "service".to_string(),
+            DEFAULT_SERVICE_RELAY,
+            &master_key,
+        );
         ServiceNwc::insert(conn, &db).unwrap();
 
         let found = ServiceNwc::find_by_user(conn, &pk).unwrap();
         assert_eq!(found.len(), 1);
         assert_eq!(found[0], db);
 
+        // a different master key can't decrypt the secret
+        let other_key = MasterKey::generate().0;
+        assert!(found[0].nwc_uri(&master_key, None).is_ok());
+        assert!(found[0].nwc_uri(&other_key, None).is_err());
+
+        // the proxy can sign as the wallet side of the connection
+        let wallet_keys = found[0].wallet_keys(&master_key).unwrap().unwrap();
+        assert_eq!(wallet_keys.public_key(), db.request_key());
+
+        // locking for a budget check leaves the connection as it was
+        conn.transaction(|conn| ServiceNwc::lock(conn, &db.request_key()))
+            .unwrap();
+        assert_eq!(ServiceNwc::find_by_user(conn, &pk).unwrap(), vec![db.clone()]);
+
+        // revoke
+        assert!(ServiceNwc::delete(conn, &db.request_key()).unwrap());
+        assert!(!ServiceNwc::delete(conn, &db.request_key()).unwrap());
+        assert!(ServiceNwc::find_by_user(conn, &pk).unwrap().is_empty());
+
+        teardown_database(&db_name);
+    }
+
+    #[test]
+    fn test_service_nwc_expiry() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
+        User::create(conn, pk).unwrap();
+
+        let master_key = MasterKey::generate().0;
+        let now = chrono::Utc::now().naive_utc();
+        let generate = |expires_at| {
+            ServiceNwc::generate(
+                pk,
+                "service".to_string(),
+                DEFAULT_SERVICE_RELAY,
+                &master_key,
+            )
+            .with_expiry(expires_at)
+        };
+        let forever = generate(None);
+        let active = generate(Some(now + chrono::Duration::hours(1)));
+        let expired = generate(Some(now - chrono::Duration::hours(1)));
+        for service in [&forever, &active, &expired] {
+            ServiceNwc::insert(conn, service).unwrap();
+        }
+
+        assert!(!forever.is_expired());
+        assert!(!active.is_expired());
+        assert!(expired.is_expired());
+
+        let keys = ServiceNwc::get_all_keys(conn).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(!keys.contains(&expired.request_key()));
+
+        let expired_keys = ServiceNwc::get_expired_keys(conn, now).unwrap();
+        assert_eq!(expired_keys, vec![expired.request_key()]);
+
+        // expired connections are still listed
+        let found = ServiceNwc::find_by_request_key(conn, &expired.request_key())
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.expires_at(), expired.expires_at());
+
+        teardown_database(&db_name);
+    }
+
+    #[test]
+    fn test_service_nwc_metadata() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
+        User::create(conn, pk).unwrap();
+
+        let master_key = MasterKey::generate().0;
+        let metadata = ServiceMetadata {
+            description: Some("Zap bot on laptop".to_string()),
+            icon_url: Some("https://example.com/icon.png".to_string()),
+            app_pubkey: Some(
+                XOnlyPublicKey::from_str(
+                    "5fa11a95186e2bdc05e047d8573721b407aaa54e5c39f93b2811f176a65ac5f8",
+                )
+                .unwrap(),
+            ),
+            labels: vec!["bots".to_string(), "laptop".to_string()],
+        };
+        assert!(metadata.validate().is_ok());
+
+        let db = ServiceNwc::generate(
+            pk,
+            "service".to_string(),
+            DEFAULT_SERVICE_RELAY,
+            &master_key,
+        )
+        .with_metadata(&metadata);
+        ServiceNwc::insert(conn, &db).unwrap();
+
+        let found = ServiceNwc::find_by_request_key(conn, &db.request_key())
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.metadata(), metadata);
+
+        // edit later
+        let edited = ServiceMetadata {
+            description: Some("Zap bot on phone".to_string()),
+            ..Default::default()
+        };
+        assert!(ServiceNwc::update_metadata(conn, &db.request_key(), &edited).unwrap());
+        let found = ServiceNwc::find_by_request_key(conn, &db.request_key())
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.metadata(), edited);
+
+        // invalid metadata
+        let invalid = ServiceMetadata {
+            icon_url: Some("javascript:alert(1)".to_string()),
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+        let invalid = ServiceMetadata {
+            labels: vec!["bots".to_string(), "bots".to_string()],
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+
+        teardown_database(&db_name);
+    }
+
+    #[test]
+    fn test_payments() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
+        User::create(conn, pk).unwrap();
+
+        let master_key = MasterKey::generate().0;
+        let db = ServiceNwc::generate(
+            pk,
+            "service".to_string(),
+            DEFAULT_SERVICE_RELAY,
+            &master_key,
+        );
+        ServiceNwc::insert(conn, &db).unwrap();
+        let service_key = db.request_key();
+
+        let request_id = EventId::from_slice(&[1; 32]).unwrap();
+        let forward_id = EventId::from_slice(&[2; 32]).unwrap();
+        let payment = |d_tag: &str, amount_msats| {
+            Payment::new(
+                service_key,
+                request_id,
+                forward_id,
+                Some(d_tag.to_string()),
+                None,
+                None,
+                amount_msats,
+            )
+        };
+        let first = payment("a", 1_000);
+        let second = payment("b", 2_000);
+        Payment::insert(conn, &first).unwrap();
+        Payment::insert(conn, &second).unwrap();
+
+        let found = Payment::find_by_forward_id(conn, &forward_id).unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|p| p.status() == PaymentStatus::Pending));
+        let long_ago = first.date_created() - chrono::Duration::hours(1);
+        assert_eq!(
+            Payment::oldest_pending(conn, long_ago).unwrap(),
+            Some(first.date_created())
+        );
+        assert_eq!(
+            Payment::spent_msats(conn, &service_key, None).unwrap(),
+            3_000
+        );
+
+        // failed payments don't count against the budget
+        Payment::settle(conn, first.id(), Some("00".repeat(32)), Some(10)).unwrap();
+        Payment::fail(conn, second.id()).unwrap();
+        assert_eq!(Payment::oldest_pending(conn, long_ago).unwrap(), None);
+        assert_eq!(
+            Payment::spent_msats(conn, &service_key, None).unwrap(),
+            1_000
+        );
+
+        let found = Payment::find_by_forward_id(conn, &forward_id).unwrap();
+        let settled = found.iter().find(|p| p.d_tag() == Some("a")).unwrap();
+        assert_eq!(settled.status(), PaymentStatus::Settled);
+        assert_eq!(settled.fees_paid_msats(), Some(10));
+        assert!(settled.date_settled().is_some());
+
+        // only payments since the start of the budget period count
+        let later = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(1);
+        assert_eq!(
+            Payment::spent_msats(conn, &service_key, Some(later)).unwrap(),
+            0
+        );
+
+        // listing only shows settled payments unless asked for all of them
+        let settled = Payment::list(
+            conn,
+            &service_key,
+            &PaymentFilter {
+                settled_only: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(settled.len(), 1);
+        assert_eq!(settled[0].id(), first.id());
+        let all = Payment::list(conn, &service_key, &PaymentFilter::default()).unwrap();
+        assert_eq!(all.len(), 2);
+        let page = Payment::list(
+            conn,
+            &service_key,
+            &PaymentFilter {
+                limit: Some(1),
+                offset: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(page.len(), 1);
+        let filter = PaymentFilter {
+            from: Some(later),
+            ..Default::default()
+        };
+        assert!(Payment::list(conn, &service_key, &filter)
+            .unwrap()
+            .is_empty());
+
+        // other connections' payments are never listed
+        let other =
+            ServiceNwc::generate(pk, "other".to_string(), DEFAULT_SERVICE_RELAY, &master_key);
+        ServiceNwc::insert(conn, &other).unwrap();
+        let filter = PaymentFilter::default();
+        assert!(Payment::list(conn, &other.request_key(), &filter)
+            .unwrap()
+            .is_empty());
+
+        // payments the wallet never answered stop counting once they are stale
+        let third = payment("c", 4_000);
+        Payment::insert(conn, &third).unwrap();
+        assert_eq!(
+            Payment::spent_msats(conn, &service_key, None).unwrap(),
+            5_000
+        );
+        assert_eq!(Payment::fail_stale(conn, long_ago).unwrap(), 0);
+        assert_eq!(Payment::fail_stale(conn, later).unwrap(), 1);
+        assert_eq!(
+            Payment::spent_msats(conn, &service_key, None).unwrap(),
+            1_000
+        );
+        let found = Payment::find_by_forward_id(conn, &forward_id).unwrap();
+        let stale = found.iter().find(|p| p.d_tag() == Some("c")).unwrap();
+        assert_eq!(stale.status(), PaymentStatus::Failed);
+        // settled payments are left alone
+        let settled = found.iter().find(|p| p.d_tag() == Some("a")).unwrap();
+        assert_eq!(settled.status(), PaymentStatus::Settled);
+
+        teardown_database(&db_name);
+    }
+
+    #[test]
+    fn test_zaps() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
+        User::create(conn, pk).unwrap();
+
+        let now = chrono::Utc::now().naive_utc();
+        let zap = |payment_hash: &str, expires_at| {
+            Zap::new(
+                payment_hash.to_string(),
+                pk,
+                "lnbc".to_string(),
+                "{}".to_string(),
+                expires_at,
+            )
+        };
+        let pending = zap("aa", now + chrono::Duration::hours(1));
+        let expired = zap("bb", now - chrono::Duration::minutes(1));
+        Zap::insert(conn, &pending).unwrap();
+        Zap::insert(conn, &expired).unwrap();
+
+        // expired invoices can't be paid anymore so aren't watched
+        let found = Zap::get_pending(conn, now).unwrap();
+        assert_eq!(found, vec![pending.clone()]);
+        // unless they only just expired
+        let found = Zap::get_pending(conn, now - chrono::Duration::minutes(2)).unwrap();
+        assert_eq!(found, vec![pending.clone(), expired.clone()]);
+        assert_eq!(found[0].user_pubkey(), pk);
+        assert_eq!(found[0].receipt_id(), None);
+        assert_eq!(Zap::count_pending(conn, &pk, now).unwrap(), 1);
+
+        // once a receipt is published it is no longer pending
+        let receipt_id = EventId::from_slice(&[3; 32]).unwrap();
+        Zap::set_receipt(conn, pending.payment_hash(), &receipt_id).unwrap();
+        assert!(Zap::get_pending(conn, now).unwrap().is_empty());
+        assert_eq!(Zap::count_pending(conn, &pk, now).unwrap(), 0);
+
+        teardown_database(&db_name);
+    }
+
+    #[test]
+    fn test_encrypt_existing_secrets() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let pk = PublicKey::from_str(PUB_KEY_STR).unwrap();
+        User::create(conn, pk).unwrap();
+
+        // simulate rows written before secrets were encrypted
+        let nwc = NostrWalletConnectURI::from_str(NWC_URI_STR).unwrap();
+        let plaintext = nwc.secret.secret_bytes().to_hex();
+        diesel::insert_into(user_nwc::table)
+            .values((
+                user_nwc::request_key.eq(nwc.public_key.to_hex()),
+                user_nwc::response_key.eq(&plaintext),
+                user_nwc::relay_url.eq(nwc.relay_url.to_string()),
+                user_nwc::user_pubkey.eq(pk.to_hex()),
+            ))
+            .execute(conn)
+            .unwrap();
+        diesel::insert_into(service_nwc::table)
+            .values((
+                service_nwc::request_key.eq(nwc.public_key.to_hex()),
+                service_nwc::response_key.eq(&plaintext),
+                service_nwc::relay_url.eq(nwc.relay_url.to_string()),
+                service_nwc::service_name.eq("service"),
+                service_nwc::user_pubkey.eq(pk.to_hex()),
+            ))
+            .execute(conn)
+            .unwrap();
+
+        let master_key = MasterKey::generate().0;
+        let migrated = super::encrypt_existing_secrets(conn, &master_key).unwrap();
+        assert_eq!(migrated, 2);
+
+        let user_nwc = UserNwc::find_by_user(conn, &pk).unwrap();
+        assert_eq!(user_nwc[0].nwc_uri(&master_key).unwrap(), nwc);
+        let service_nwc = ServiceNwc::find_by_user(conn, &pk).unwrap();
+        assert_eq!(
+            service_nwc[0].nwc_uri(&master_key, None).unwrap().secret,
+            nwc.secret
+        );
+
+        // running again is a no-op
+        let migrated = super::encrypt_existing_secrets(conn, &master_key).unwrap();
+        assert_eq!(migrated, 0);
+
         teardown_database(&db_name);
     }
 }
@@ -0,0 +1,325 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use bitcoin::hashes::hex::ToHex;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use nostr::key::XOnlyPublicKey;
+use nostr::EventId;
+use serde::{Deserialize, Serialize};
+
+use super::schema::payments;
+use super::DbConnection;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentStatus {
+    /// Forwarded to the user's wallet, waiting on a response
+    Pending,
+    Settled,
+    Failed,
+}
+
+impl PaymentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentStatus::Pending => "pending",
+            PaymentStatus::Settled => "settled",
+            PaymentStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for PaymentStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(PaymentStatus::Pending),
+            "settled" => Ok(PaymentStatus::Settled),
+            "failed" => Ok(PaymentStatus::Failed),
+            _ => Err(anyhow!("Unknown payment status {s}")),
+        }
+    }
+}
+
+impl fmt::Display for PaymentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Which of a service connection's payments to list
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PaymentFilter {
+    pub from: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    /// Only list settled payments
+    pub settled_only: bool,
+}
+
+/// A payment a service asked for that was forwarded to the user's wallet
+#[derive(Queryable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(primary_key(id))]
+#[diesel(treat_none_as_default_value = false)]
+#[diesel(table_name = payments)]
+pub struct Payment {
+    id: String,
+    service_key: String,
+    /// Id of the service's request event
+    request_id: String,
+    /// Id of the event we forwarded to the user's wallet
+    forward_id: String,
+    /// Identifies the payment in responses to multi payment requests
+    d_tag: Option<String>,
+    invoice: Option<String>,
+    payment_hash: Option<String>,
+    amount_msats: i64,
+    status: String,
+    preimage: Option<String>,
+    fees_paid_msats: Option<i64>,
+    date_created: NaiveDateTime,
+    date_settled: Option<NaiveDateTime>,
+}
+
+impl Payment {
+    pub fn new(
+        service_key: XOnlyPublicKey,
+        request_id: EventId,
+        forward_id: EventId,
+        d_tag: Option<String>,
+        invoice: Option<String>,
+        payment_hash: Option<String>,
+        amount_msats: u64,
+    ) -> Self {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+
+        Payment {
+            id: id.to_hex(),
+            service_key: service_key.to_hex(),
+            request_id: request_id.to_hex(),
+            forward_id: forward_id.to_hex(),
+            d_tag,
+            invoice,
+            payment_hash,
+            amount_msats: amount_msats.min(i64::MAX as u64) as i64,
+            status: PaymentStatus::Pending.to_string(),
+            preimage: None,
+            fees_paid_msats: None,
+            date_created: chrono::Utc::now().naive_utc(),
+            date_settled: None,
+        }
+    }
+
+    /// Sets when the payment was made, for payments restored from an export
+    pub fn created_at(self, date_created: NaiveDateTime) -> Self {
+        Self {
+            date_created,
+            ..self
+        }
+    }
+
+    /// Sets how the wallet answered, for payments restored from an export
+    pub fn with_result(
+        self,
+        status: PaymentStatus,
+        preimage: Option<String>,
+        fees_paid_msats: Option<u64>,
+        date_settled: Option<NaiveDateTime>,
+    ) -> Self {
+        Self {
+            status: status.to_string(),
+            preimage,
+            fees_paid_msats: fees_paid_msats.map(|fees| fees.min(i64::MAX as u64) as i64),
+            date_settled,
+            ..self
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn service_key(&self) -> XOnlyPublicKey {
+        XOnlyPublicKey::from_str(&self.service_key).expect("invalid service key")
+    }
+
+    pub fn request_id(&self) -> EventId {
+        EventId::from_hex(&self.request_id).expect("invalid request id")
+    }
+
+    pub fn forward_id(&self) -> EventId {
+        EventId::from_hex(&self.forward_id).expect("invalid forward id")
+    }
+
+    pub fn d_tag(&self) -> Option<&str> {
+        self.d_tag.as_deref()
+    }
+
+    pub fn invoice(&self) -> Option<&str> {
+        self.invoice.as_deref()
+    }
+
+    pub fn payment_hash(&self) -> Option<&str> {
+        self.payment_hash.as_deref()
+    }
+
+    pub fn amount_msats(&self) -> u64 {
+        self.amount_msats as u64
+    }
+
+    pub fn status(&self) -> PaymentStatus {
+        PaymentStatus::from_str(&self.status).expect("invalid payment status")
+    }
+
+    pub fn preimage(&self) -> Option<&str> {
+        self.preimage.as_deref()
+    }
+
+    pub fn fees_paid_msats(&self) -> Option<u64> {
+        self.fees_paid_msats.map(|fees| fees as u64)
+    }
+
+    pub fn date_created(&self) -> NaiveDateTime {
+        self.date_created
+    }
+
+    pub fn date_settled(&self) -> Option<NaiveDateTime> {
+        self.date_settled
+    }
+
+    pub fn insert(conn: &mut DbConnection, payment: &Self) -> Result<(), diesel::result::Error> {
+        diesel::insert_into(payments::table)
+            .values(payment)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, diesel::result::Error> {
+        payments::table
+            .order((payments::date_created, payments::id))
+            .load::<Self>(conn)
+    }
+
+    pub fn find_by_forward_id(
+        conn: &mut DbConnection,
+        forward_id: &EventId,
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        payments::table
+            .filter(payments::forward_id.eq(forward_id.to_hex()))
+            .load::<Self>(conn)
+    }
+
+    /// Payments of a service connection, newest first
+    pub fn list(
+        conn: &mut DbConnection,
+        service_key: &XOnlyPublicKey,
+        filter: &PaymentFilter,
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        let mut query = payments::table
+            .filter(payments::service_key.eq(service_key.to_hex()))
+            .order((payments::date_created.desc(), payments::id.asc()))
+            .into_boxed();
+        if let Some(from) = filter.from {
+            query = query.filter(payments::date_created.ge(from));
+        }
+        if let Some(until) = filter.until {
+            query = query.filter(payments::date_created.le(until));
+        }
+        if filter.settled_only {
+            query = query.filter(payments::status.eq(PaymentStatus::Settled.as_str()));
+        }
+        if let Some(limit) = filter.limit {
+            query = query.limit(limit);
+        }
+        if let Some(offset) = filter.offset {
+            query = query.offset(offset);
+        }
+
+        query.load::<Self>(conn)
+    }
+
+    /// Marks the payment as paid by the user's wallet
+    pub fn settle(
+        conn: &mut DbConnection,
+        id: &str,
+        preimage: Option<String>,
+        fees_paid_msats: Option<u64>,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::update(payments::table.find(id))
+            .set((
+                payments::status.eq(PaymentStatus::Settled.as_str()),
+                payments::preimage.eq(preimage),
+                payments::fees_paid_msats.eq(fees_paid_msats.map(|fees| fees as i64)),
+                payments::date_settled.eq(chrono::Utc::now().naive_utc()),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Marks the payment as failed, so it no longer counts against the budget
+    pub fn fail(conn: &mut DbConnection, id: &str) -> Result<(), diesel::result::Error> {
+        diesel::update(payments::table.find(id))
+            .set(payments::status.eq(PaymentStatus::Failed.as_str()))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Fails payments made before `before` that are still waiting on the wallet, so a wallet that
+    /// never answers doesn't hold on to the budget. Returns how many were failed.
+    pub fn fail_stale(
+        conn: &mut DbConnection,
+        before: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(
+            payments::table
+                .filter(payments::status.eq(PaymentStatus::Pending.as_str()))
+                .filter(payments::date_created.lt(before)),
+        )
+        .set(payments::status.eq(PaymentStatus::Failed.as_str()))
+        .execute(conn)
+    }
+
+    /// When the oldest payment still waiting on the wallet was made, ignoring those from before `since`
+    pub fn oldest_pending(
+        conn: &mut DbConnection,
+        since: NaiveDateTime,
+    ) -> Result<Option<NaiveDateTime>, diesel::result::Error> {
+        payments::table
+            .filter(payments::status.eq(PaymentStatus::Pending.as_str()))
+            .filter(payments::date_created.ge(since))
+            .select(diesel::dsl::min(payments::date_created))
+            .first(conn)
+    }
+
+    /// Total of pending and settled payments for a service connection, optionally
+    /// only counting those made since the given time
+    pub fn spent_msats(
+        conn: &mut DbConnection,
+        service_key: &XOnlyPublicKey,
+        since: Option<NaiveDateTime>,
+    ) -> Result<u64, diesel::result::Error> {
+        let mut query = payments::table
+            .filter(payments::service_key.eq(service_key.to_hex()))
+            .filter(payments::status.ne(PaymentStatus::Failed.as_str()))
+            .select(payments::amount_msats)
+            .into_boxed();
+        if let Some(since) = since {
+            query = query.filter(payments::date_created.ge(since));
+        }
+
+        let amounts = query.load::<i64>(conn)?;
+        Ok(amounts
+            .into_iter()
+            .fold(0u64, |total, amount| total.saturating_add(amount as u64)))
+    }
+}
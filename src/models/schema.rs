@@ -1,5 +1,23 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    payments (id) {
+        id -> Text,
+        service_key -> Text,
+        request_id -> Text,
+        forward_id -> Text,
+        d_tag -> Nullable<Text>,
+        invoice -> Nullable<Text>,
+        payment_hash -> Nullable<Text>,
+        amount_msats -> BigInt,
+        status -> Text,
+        preimage -> Nullable<Text>,
+        fees_paid_msats -> Nullable<BigInt>,
+        date_created -> Timestamp,
+        date_settled -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     service_nwc (request_key) {
         request_key -> Text,
@@ -8,6 +26,15 @@ diesel::table! {
         service_name -> Text,
         user_pubkey -> Text,
         date_created -> Timestamp,
+        wallet_key -> Nullable<Text>,
+        expires_at -> Nullable<Timestamp>,
+        description -> Nullable<Text>,
+        icon_url -> Nullable<Text>,
+        app_pubkey -> Nullable<Text>,
+        labels -> Text,
+        max_payment_sats -> Nullable<BigInt>,
+        budget_sats -> Nullable<BigInt>,
+        budget_renewal -> Nullable<Text>,
     }
 }
 
@@ -18,6 +45,8 @@ diesel::table! {
         relay_url -> Text,
         user_pubkey -> Text,
         date_created -> Timestamp,
+        lud16 -> Nullable<Text>,
+        fallback_relays -> Text,
     }
 }
 
@@ -25,10 +54,26 @@ diesel::table! {
     users (pubkey) {
         pubkey -> Text,
         date_created -> Timestamp,
+        lud16 -> Nullable<Text>,
+        username -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    zaps (payment_hash) {
+        payment_hash -> Text,
+        user_pubkey -> Text,
+        invoice -> Text,
+        request -> Text,
+        receipt_id -> Nullable<Text>,
+        expires_at -> Timestamp,
+        date_created -> Timestamp,
     }
 }
 
+diesel::joinable!(payments -> service_nwc (service_key));
 diesel::joinable!(service_nwc -> users (user_pubkey));
 diesel::joinable!(user_nwc -> users (user_pubkey));
+diesel::joinable!(zaps -> users (user_pubkey));
 
-diesel::allow_tables_to_appear_in_same_query!(service_nwc, user_nwc, users,);
+diesel::allow_tables_to_appear_in_same_query!(payments, service_nwc, user_nwc, users, zaps,);
@@ -1,20 +1,26 @@
 use std::str::FromStr;
 
+use anyhow::anyhow;
 use bitcoin::hashes::hex::ToHex;
 use bitcoin::secp256k1::PublicKey;
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use diesel::result::Error::DeserializationError;
 use nostr::key::{SecretKey, XOnlyPublicKey};
 use nostr::nips::nip47::NostrWalletConnectURI;
-use nostr::Keys;
+use nostr::{Keys, Url};
 use serde::{Deserialize, Serialize};
 
 use super::schema::service_nwc;
+use super::DbConnection;
+use crate::encryption::MasterKey;
+use crate::spending::MAX_AMOUNT_MSATS;
 
 pub const DEFAULT_SERVICE_RELAY: &str = "wss://relay.damus.io";
 
 #[derive(Queryable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[diesel(primary_key(request_key))]
+#[diesel(treat_none_as_default_value = false)]
 #[diesel(table_name = service_nwc)]
 pub struct ServiceNwc {
     request_key: String,
@@ -22,21 +28,237 @@ pub struct ServiceNwc {
     relay_url: String,
     service_name: String,
     user_pubkey: String,
-    date_created: String,
+    date_created: NaiveDateTime,
+    /// Encrypted secret for `request_key`, missing for connections created before it was stored
+    wallet_key: Option<String>,
+    expires_at: Option<NaiveDateTime>,
+    description: Option<String>,
+    icon_url: Option<String>,
+    app_pubkey: Option<String>,
+    /// JSON encoded list of labels
+    labels: String,
+    max_payment_sats: Option<i64>,
+    budget_sats: Option<i64>,
+    budget_renewal: Option<String>,
+}
+
+/// How often a connection's budget starts over
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetRenewal {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+    /// The budget is for the lifetime of the connection
+    #[default]
+    Never,
+}
+
+impl BudgetRenewal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BudgetRenewal::Daily => "daily",
+            BudgetRenewal::Weekly => "weekly",
+            BudgetRenewal::Monthly => "monthly",
+            BudgetRenewal::Yearly => "yearly",
+            BudgetRenewal::Never => "never",
+        }
+    }
+
+    /// Start of the current budget period, payments before it don't count against the budget
+    pub fn period_start(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let days = match self {
+            BudgetRenewal::Daily => 1,
+            BudgetRenewal::Weekly => 7,
+            BudgetRenewal::Monthly => 30,
+            BudgetRenewal::Yearly => 365,
+            BudgetRenewal::Never => return None,
+        };
+        Some(now - chrono::Duration::days(days))
+    }
+}
+
+impl FromStr for BudgetRenewal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(BudgetRenewal::Daily),
+            "weekly" => Ok(BudgetRenewal::Weekly),
+            "monthly" => Ok(BudgetRenewal::Monthly),
+            "yearly" => Ok(BudgetRenewal::Yearly),
+            "never" => Ok(BudgetRenewal::Never),
+            _ => Err(anyhow!("Unknown budget renewal {s}")),
+        }
+    }
+}
+
+/// Spending limits of a connection, unset limits fall back to the configured policy
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpendingLimits {
+    /// Maximum amount for a single payment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_payment_sats: Option<u64>,
+    /// Maximum total of payments per budget period
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_sats: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_renewal: Option<BudgetRenewal>,
+}
+
+impl SpendingLimits {
+    /// Limits are checked in msats, which are stored as signed 64 bit integers
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let max_sats = MAX_AMOUNT_MSATS / 1_000;
+        if self.max_payment_sats.is_some_and(|sats| sats > max_sats)
+            || self.budget_sats.is_some_and(|sats| sats > max_sats)
+        {
+            return Err(anyhow!("Spending limits can be at most {max_sats} sats"));
+        }
+
+        Ok(())
+    }
+}
+
+const MAX_DESCRIPTION_LEN: usize = 256;
+const MAX_LABELS: usize = 16;
+const MAX_LABEL_LEN: usize = 64;
+
+/// Details that help a user tell their service connections apart
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+    /// Pubkey of the app that requested the connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_pubkey: Option<XOnlyPublicKey>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+}
+
+impl ServiceMetadata {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(description) = &self.description {
+            if description.len() > MAX_DESCRIPTION_LEN {
+                return Err(anyhow!(
+                    "Description is longer than {MAX_DESCRIPTION_LEN} bytes"
+                ));
+            }
+        }
+
+        if let Some(icon_url) = &self.icon_url {
+            let url = Url::parse(icon_url).map_err(|e| anyhow!("Invalid icon url: {e}"))?;
+            if url.scheme() != "https" && url.scheme() != "http" {
+                return Err(anyhow!("Icon url must be http or https"));
+            }
+        }
+
+        if self.labels.len() > MAX_LABELS {
+            return Err(anyhow!("At most {MAX_LABELS} labels are allowed"));
+        }
+        for (i, label) in self.labels.iter().enumerate() {
+            if label.trim().is_empty() || label.len() > MAX_LABEL_LEN {
+                return Err(anyhow!(
+                    "Labels must be between 1 and {MAX_LABEL_LEN} bytes"
+                ));
+            }
+            if self.labels[..i].contains(label) {
+                return Err(anyhow!("Duplicate label {label}"));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl ServiceNwc {
-    pub fn generate(user_pubkey: PublicKey, service_name: String) -> ServiceNwc {
+    pub fn generate(
+        user_pubkey: PublicKey,
+        service_name: String,
+        relay_url: &str,
+        master_key: &MasterKey,
+    ) -> ServiceNwc {
         let request_key = Keys::generate();
         let response_key = Keys::generate();
 
         ServiceNwc {
             request_key: request_key.public_key().to_hex(),
-            response_key: response_key.secret_key().unwrap().secret_bytes().to_hex(),
-            relay_url: DEFAULT_SERVICE_RELAY.to_string(),
+            response_key: master_key.encrypt(&response_key.secret_key().unwrap().secret_bytes()),
+            relay_url: relay_url.to_string(),
+            service_name,
+            user_pubkey: user_pubkey.to_hex(),
+            date_created: chrono::Utc::now().naive_utc(),
+            wallet_key: Some(master_key.encrypt(&request_key.secret_key().unwrap().secret_bytes())),
+            expires_at: None,
+            description: None,
+            icon_url: None,
+            app_pubkey: None,
+            labels: encode_labels(&[]),
+            max_payment_sats: None,
+            budget_sats: None,
+            budget_renewal: None,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: &SpendingLimits) -> Self {
+        let sats = |sats: u64| sats.min(i64::MAX as u64) as i64;
+        self.max_payment_sats = limits.max_payment_sats.map(sats);
+        self.budget_sats = limits.budget_sats.map(sats);
+        self.budget_renewal = limits.budget_renewal.map(|r| r.as_str().to_string());
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: &ServiceMetadata) -> Self {
+        self.description = metadata.description.clone();
+        self.icon_url = metadata.icon_url.clone();
+        self.app_pubkey = metadata.app_pubkey.map(|key| key.to_hex());
+        self.labels = encode_labels(&metadata.labels);
+        self
+    }
+
+    /// Sets when the connection stops working, `None` never expires
+    pub fn with_expiry(mut self, expires_at: Option<NaiveDateTime>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    /// Sets the secret for the wallet side of the connection, used when importing
+    pub fn with_wallet_key(
+        mut self,
+        wallet_key: Option<SecretKey>,
+        master_key: &MasterKey,
+    ) -> Self {
+        self.wallet_key = wallet_key.map(|key| master_key.encrypt(&key.secret_bytes()));
+        self
+    }
+
+    /// Recreates a service connection from its NWC uri, used when importing
+    pub fn from_nwc_uri(
+        nwc_uri: &NostrWalletConnectURI,
+        user_pubkey: PublicKey,
+        service_name: String,
+        master_key: &MasterKey,
+        date_created: NaiveDateTime,
+    ) -> ServiceNwc {
+        ServiceNwc {
+            request_key: nwc_uri.public_key.to_hex(),
+            response_key: master_key.encrypt(&nwc_uri.secret.secret_bytes()),
+            relay_url: nwc_uri.relay_url.to_string(),
             service_name,
             user_pubkey: user_pubkey.to_hex(),
-            date_created: chrono::Utc::now().naive_utc().to_string(),
+            date_created,
+            wallet_key: None,
+            expires_at: None,
+            description: None,
+            icon_url: None,
+            app_pubkey: None,
+            labels: encode_labels(&[]),
+            max_payment_sats: None,
+            budget_sats: None,
+            budget_renewal: None,
         }
     }
 
@@ -44,25 +266,87 @@ impl ServiceNwc {
         PublicKey::from_str(&self.user_pubkey).expect("invalid pubkey")
     }
 
+    pub fn service_name(&self) -> &str {
+        &self.service_name
+    }
+
+    pub fn relay_url(&self) -> &str {
+        &self.relay_url
+    }
+
+    pub fn date_created(&self) -> NaiveDateTime {
+        self.date_created
+    }
+
+    pub fn expires_at(&self) -> Option<NaiveDateTime> {
+        self.expires_at
+    }
+
+    pub fn metadata(&self) -> ServiceMetadata {
+        ServiceMetadata {
+            description: self.description.clone(),
+            icon_url: self.icon_url.clone(),
+            app_pubkey: self
+                .app_pubkey
+                .as_deref()
+                .map(|key| XOnlyPublicKey::from_str(key).expect("invalid app pubkey")),
+            labels: serde_json::from_str(&self.labels).expect("invalid labels"),
+        }
+    }
+
+    pub fn limits(&self) -> SpendingLimits {
+        SpendingLimits {
+            max_payment_sats: self.max_payment_sats.map(|sats| sats as u64),
+            budget_sats: self.budget_sats.map(|sats| sats as u64),
+            budget_renewal: self
+                .budget_renewal
+                .as_deref()
+                .map(|r| BudgetRenewal::from_str(r).expect("invalid budget renewal")),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= chrono::Utc::now().naive_utc())
+    }
+
     pub fn request_key(&self) -> XOnlyPublicKey {
         XOnlyPublicKey::from_str(&self.request_key).expect("invalid request key")
     }
 
-    pub fn response_key(&self) -> SecretKey {
-        SecretKey::from_str(&self.response_key).expect("invalid response key")
+    pub fn response_key(&self, master_key: &MasterKey) -> anyhow::Result<SecretKey> {
+        let bytes = master_key.decrypt(&self.response_key)?;
+        Ok(SecretKey::from_slice(&bytes)?)
     }
 
-    pub fn nwc_uri(&self) -> NostrWalletConnectURI {
+    /// Keys for the wallet side of the connection, used to sign responses to the service
+    pub fn wallet_keys(&self, master_key: &MasterKey) -> anyhow::Result<Option<Keys>> {
+        match &self.wallet_key {
+            Some(wallet_key) => {
+                let bytes = master_key.decrypt(wallet_key)?;
+                Ok(Some(Keys::new(SecretKey::from_slice(&bytes)?)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The uri given to the service, `lud16` is the user's lightning address which is
+    /// looked up when issuing rather than stored so it follows changes to the user's address
+    pub fn nwc_uri(
+        &self,
+        master_key: &MasterKey,
+        lud16: Option<String>,
+    ) -> anyhow::Result<NostrWalletConnectURI> {
         let relay_url = self.relay_url.clone().parse().expect("invalid relay url");
-        NostrWalletConnectURI {
+        Ok(NostrWalletConnectURI {
             public_key: self.request_key(),
-            secret: self.response_key(),
+            secret: self.response_key(master_key)?,
             relay_url,
-            lud16: None,
-        }
+            lud16,
+        })
     }
 
-    pub fn insert(conn: &mut SqliteConnection, db: &Self) -> Result<(), diesel::result::Error> {
+    pub fn insert(conn: &mut DbConnection, db: &Self) -> Result<(), diesel::result::Error> {
         diesel::insert_into(service_nwc::table)
             .values(db)
             .execute(conn)?;
@@ -70,8 +354,34 @@ impl ServiceNwc {
         Ok(())
     }
 
+    /// Locks the connection's row until the transaction ends, so its budget is checked and
+    /// spent by one request at a time across every proxy using the database. SQLite can only
+    /// lock the whole database, which the first write of a transaction does.
+    pub fn lock(
+        conn: &mut DbConnection,
+        request_key: &XOnlyPublicKey,
+    ) -> Result<(), diesel::result::Error> {
+        let key = request_key.to_hex();
+        match conn {
+            DbConnection::Postgres(conn) => {
+                service_nwc::table
+                    .find(&key)
+                    .select(service_nwc::request_key)
+                    .for_update()
+                    .first::<String>(conn)?;
+            }
+            DbConnection::Sqlite(conn) => {
+                diesel::update(service_nwc::table.find(&key))
+                    .set(service_nwc::request_key.eq(&key))
+                    .execute(conn)?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn find_by_request_key(
-        conn: &mut SqliteConnection,
+        conn: &mut DbConnection,
         request_key: &XOnlyPublicKey,
     ) -> Result<Option<Self>, diesel::result::Error> {
         let result = service_nwc::table
@@ -86,7 +396,7 @@ impl ServiceNwc {
     }
 
     pub fn find_by_user(
-        conn: &mut SqliteConnection,
+        conn: &mut DbConnection,
         user_pubkey: &PublicKey,
     ) -> Result<Vec<Self>, diesel::result::Error> {
         let found = service_nwc::table
@@ -96,20 +406,119 @@ impl ServiceNwc {
         Ok(found)
     }
 
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, diesel::result::Error> {
+        service_nwc::table
+            .order(service_nwc::date_created)
+            .load::<Self>(conn)
+    }
+
+    pub fn count(conn: &mut DbConnection) -> Result<i64, diesel::result::Error> {
+        service_nwc::table.count().get_result(conn)
+    }
+
+    /// Replaces the metadata of a service connection, returns false if it does not exist
+    pub fn update_metadata(
+        conn: &mut DbConnection,
+        request_key: &XOnlyPublicKey,
+        metadata: &ServiceMetadata,
+    ) -> Result<bool, diesel::result::Error> {
+        let updated = diesel::update(service_nwc::table.find(request_key.to_hex()))
+            .set((
+                service_nwc::description.eq(&metadata.description),
+                service_nwc::icon_url.eq(&metadata.icon_url),
+                service_nwc::app_pubkey.eq(metadata.app_pubkey.map(|key| key.to_hex())),
+                service_nwc::labels.eq(encode_labels(&metadata.labels)),
+            ))
+            .execute(conn)?;
+
+        Ok(updated > 0)
+    }
+
+    /// Deletes the service connection, returns false if it did not exist
+    pub fn delete(
+        conn: &mut DbConnection,
+        request_key: &XOnlyPublicKey,
+    ) -> Result<bool, diesel::result::Error> {
+        let deleted =
+            diesel::delete(service_nwc::table.find(request_key.to_hex())).execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Relays services send their requests to
+    pub fn get_relays(conn: &mut DbConnection) -> Result<Vec<String>, diesel::result::Error> {
+        let found = service_nwc::table
+            .select(service_nwc::relay_url)
+            .distinct()
+            .load::<String>(conn)?;
+
+        Ok(found)
+    }
+
+    /// Keys of all connections that have not expired
     pub fn get_all_keys(
-        conn: &mut SqliteConnection,
+        conn: &mut DbConnection,
     ) -> Result<Vec<XOnlyPublicKey>, diesel::result::Error> {
+        let now = chrono::Utc::now().naive_utc();
         let found = service_nwc::table
+            .filter(
+                service_nwc::expires_at
+                    .is_null()
+                    .or(service_nwc::expires_at.gt(now)),
+            )
             .select(service_nwc::request_key)
             .load::<String>(conn)?;
 
-        let mut keys = vec![];
-        for str in found {
-            let key =
-                XOnlyPublicKey::from_str(&str).map_err(|e| DeserializationError(Box::new(e)))?;
-            keys.push(key);
+        parse_keys(found)
+    }
+
+    /// Keys of all connections that expired at or before `now`
+    pub fn get_expired_keys(
+        conn: &mut DbConnection,
+        now: NaiveDateTime,
+    ) -> Result<Vec<XOnlyPublicKey>, diesel::result::Error> {
+        let found = service_nwc::table
+            .filter(service_nwc::expires_at.le(now))
+            .select(service_nwc::request_key)
+            .load::<String>(conn)?;
+
+        parse_keys(found)
+    }
+
+    /// Encrypts any response keys that are still stored as plaintext hex.
+    /// Returns the number of rows that were migrated.
+    pub fn encrypt_plaintext_keys(
+        conn: &mut DbConnection,
+        master_key: &MasterKey,
+    ) -> Result<usize, diesel::result::Error> {
+        let found = service_nwc::table
+            .select((service_nwc::request_key, service_nwc::response_key))
+            .load::<(String, String)>(conn)?;
+
+        let mut count = 0;
+        for (request_key, response_key) in found {
+            if let Ok(secret) = SecretKey::from_str(&response_key) {
+                diesel::update(service_nwc::table.find(request_key))
+                    .set(service_nwc::response_key.eq(master_key.encrypt(&secret.secret_bytes())))
+                    .execute(conn)?;
+                count += 1;
+            }
         }
 
-        Ok(keys)
+        Ok(count)
     }
 }
+
+fn parse_keys(found: Vec<String>) -> Result<Vec<XOnlyPublicKey>, diesel::result::Error> {
+    let mut keys = vec![];
+    for str in found {
+        let key = XOnlyPublicKey::from_str(&str).map_err(|e| DeserializationError(Box::new(e)))?;
+        keys.push(key);
+    }
+
+    Ok(keys)
+}
+
+fn encode_labels(labels: &[String]) -> String {
+    serde_json::to_string(labels).expect("labels are serializable")
+}
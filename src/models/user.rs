@@ -1,31 +1,106 @@
 use std::str::FromStr;
 
+use anyhow::anyhow;
 use bitcoin::hashes::hex::ToHex;
 use bitcoin::secp256k1::PublicKey;
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use super::schema::users;
+use super::schema::{user_nwc, users};
+use super::DbConnection;
+
+const MAX_LUD16_LEN: usize = 320;
+const MAX_USERNAME_LEN: usize = 64;
+
+/// Checks a username can be used as the name part of a lightning address on the proxy
+pub fn validate_username(username: &str) -> anyhow::Result<()> {
+    if username.is_empty() || username.len() > MAX_USERNAME_LEN {
+        return Err(anyhow!(
+            "Username must be 1 to {MAX_USERNAME_LEN} characters"
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c))
+    {
+        return Err(anyhow!(
+            "Username can only contain lowercase letters, digits, '-', '_' and '.'"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks a lightning address looks like `name@domain`
+pub fn validate_lud16(lud16: &str) -> anyhow::Result<()> {
+    if lud16.len() > MAX_LUD16_LEN {
+        return Err(anyhow!("Lightning address is too long"));
+    }
+    let Some((name, domain)) = lud16.split_once('@') else {
+        return Err(anyhow!("Lightning address must be name@domain"));
+    };
+    let valid_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.+".contains(c));
+    let valid_domain = domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !valid_name || !valid_domain {
+        return Err(anyhow!("Invalid lightning address {lud16}"));
+    }
+
+    Ok(())
+}
 
 #[derive(Queryable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[diesel(primary_key(pubkey))]
+#[diesel(treat_none_as_default_value = false)]
 pub struct User {
     pubkey: String,
-    date_created: String,
+    date_created: NaiveDateTime,
+    /// Lightning address set for the user, overrides the one from their wallet
+    lud16: Option<String>,
+    /// Name of the user's lightning address hosted by the proxy
+    username: Option<String>,
 }
 
 impl User {
-    pub(crate) fn pubkey(&self) -> PublicKey {
+    pub fn pubkey(&self) -> PublicKey {
         PublicKey::from_str(&self.pubkey).expect("invalid pubkey")
     }
 
+    pub fn date_created(&self) -> NaiveDateTime {
+        self.date_created
+    }
+
+    pub fn lud16(&self) -> Option<&str> {
+        self.lud16.as_deref()
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
     pub fn create(
-        conn: &mut SqliteConnection,
+        conn: &mut DbConnection,
         pubkey: PublicKey,
+    ) -> Result<Self, diesel::result::Error> {
+        Self::create_at(conn, pubkey, chrono::Utc::now().naive_utc())
+    }
+
+    pub fn create_at(
+        conn: &mut DbConnection,
+        pubkey: PublicKey,
+        date_created: NaiveDateTime,
     ) -> Result<Self, diesel::result::Error> {
         let user = Self {
             pubkey: pubkey.to_hex(),
-            date_created: chrono::Utc::now().naive_utc().to_string(),
+            date_created,
+            lud16: None,
+            username: None,
         };
 
         diesel::insert_into(users::table)
@@ -36,7 +111,7 @@ impl User {
     }
 
     pub fn find(
-        conn: &mut SqliteConnection,
+        conn: &mut DbConnection,
         pubkey: &PublicKey,
     ) -> Result<Option<Self>, diesel::result::Error> {
         let result = users::table
@@ -49,4 +124,69 @@ impl User {
             Err(e) => Err(e),
         }
     }
+
+    pub fn find_by_username(
+        conn: &mut DbConnection,
+        username: &str,
+    ) -> Result<Option<Self>, diesel::result::Error> {
+        users::table
+            .filter(users::username.eq(username))
+            .first::<Self>(conn)
+            .optional()
+    }
+
+    /// Sets or clears the user's username, returns false if the user doesn't exist.
+    /// Fails if another user already has the username.
+    pub fn set_username(
+        conn: &mut DbConnection,
+        pubkey: &PublicKey,
+        username: Option<&str>,
+    ) -> Result<bool, diesel::result::Error> {
+        let updated = diesel::update(users::table.find(pubkey.to_hex()))
+            .set(users::username.eq(username))
+            .execute(conn)?;
+
+        Ok(updated > 0)
+    }
+
+    /// Sets or clears the user's lightning address, returns false if the user doesn't exist
+    pub fn set_lud16(
+        conn: &mut DbConnection,
+        pubkey: &PublicKey,
+        lud16: Option<&str>,
+    ) -> Result<bool, diesel::result::Error> {
+        let updated = diesel::update(users::table.find(pubkey.to_hex()))
+            .set(users::lud16.eq(lud16))
+            .execute(conn)?;
+
+        Ok(updated > 0)
+    }
+
+    /// The user's lightning address, the one set for them or else the one from their newest wallet
+    pub fn lightning_address(
+        conn: &mut DbConnection,
+        pubkey: &PublicKey,
+    ) -> Result<Option<String>, diesel::result::Error> {
+        if let Some(lud16) = Self::find(conn, pubkey)?.and_then(|user| user.lud16) {
+            return Ok(Some(lud16));
+        }
+
+        let from_wallet = user_nwc::table
+            .filter(user_nwc::user_pubkey.eq(pubkey.to_hex()))
+            .filter(user_nwc::lud16.is_not_null())
+            .order(user_nwc::date_created.desc())
+            .select(user_nwc::lud16)
+            .first::<Option<String>>(conn)
+            .optional()?;
+
+        Ok(from_wallet.flatten())
+    }
+
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, diesel::result::Error> {
+        users::table.order(users::date_created).load::<Self>(conn)
+    }
+
+    pub fn count(conn: &mut DbConnection) -> Result<i64, diesel::result::Error> {
+        users::table.count().get_result(conn)
+    }
 }
@@ -2,53 +2,104 @@ use std::str::FromStr;
 
 use bitcoin::hashes::hex::ToHex;
 use bitcoin::secp256k1::PublicKey;
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use diesel::result::Error::DeserializationError;
 use nostr::key::{SecretKey, XOnlyPublicKey};
 use nostr::nips::nip47::NostrWalletConnectURI;
+use nostr::Url;
 use serde::{Deserialize, Serialize};
 
 use super::schema::user_nwc;
+use super::DbConnection;
+use crate::encryption::MasterKey;
 
 #[derive(Queryable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[diesel(primary_key(request_key))]
+#[diesel(treat_none_as_default_value = false)]
 #[diesel(table_name = user_nwc)]
 pub struct UserNwc {
     request_key: String,
     response_key: String,
     relay_url: String,
     user_pubkey: String,
-    date_created: String,
+    date_created: NaiveDateTime,
+    /// Lightning address from the wallet's NWC uri
+    lud16: Option<String>,
+    /// JSON encoded list of the other relays in the wallet's NWC uri
+    fallback_relays: String,
 }
 
 impl UserNwc {
-    pub(crate) fn user_pubkey(&self) -> PublicKey {
+    pub fn user_pubkey(&self) -> PublicKey {
         PublicKey::from_str(&self.user_pubkey).expect("invalid pubkey")
     }
 
-    pub fn nwc_uri(&self) -> NostrWalletConnectURI {
+    pub fn request_key(&self) -> XOnlyPublicKey {
+        XOnlyPublicKey::from_str(&self.request_key).expect("invalid request key")
+    }
+
+    pub fn relay_url(&self) -> &str {
+        &self.relay_url
+    }
+
+    pub fn date_created(&self) -> NaiveDateTime {
+        self.date_created
+    }
+
+    pub fn lud16(&self) -> Option<&str> {
+        self.lud16.as_deref()
+    }
+
+    pub fn fallback_relays(&self) -> Vec<Url> {
+        serde_json::from_str(&self.fallback_relays).expect("invalid fallback relays")
+    }
+
+    /// The wallet's relay followed by its fallbacks, in the order to try them
+    pub fn relays(&self) -> Vec<Url> {
+        let mut relays = vec![self.relay_url.parse().expect("invalid relay url")];
+        relays.extend(self.fallback_relays());
+        relays
+    }
+
+    /// Decrypts the stored secret, this should only be kept in memory
+    pub fn nwc_uri(&self, master_key: &MasterKey) -> anyhow::Result<NostrWalletConnectURI> {
         let public_key = XOnlyPublicKey::from_str(&self.request_key).expect("invalid request key");
-        let secret = SecretKey::from_str(&self.response_key).expect("invalid response key");
+        let secret = SecretKey::from_slice(&master_key.decrypt(&self.response_key)?)?;
         let relay_url = self.relay_url.clone().parse().expect("invalid relay url");
-        NostrWalletConnectURI {
+        Ok(NostrWalletConnectURI {
             public_key,
             secret,
             relay_url,
-            lud16: None,
-        }
+            lud16: self.lud16.clone(),
+        })
     }
 
     pub fn create(
-        conn: &mut SqliteConnection,
+        conn: &mut DbConnection,
+        nwc_uri: NostrWalletConnectURI,
+        user_pubkey: PublicKey,
+        master_key: &MasterKey,
+    ) -> Result<Self, diesel::result::Error> {
+        let now = chrono::Utc::now().naive_utc();
+        Self::create_at(conn, nwc_uri, user_pubkey, master_key, now)
+    }
+
+    pub fn create_at(
+        conn: &mut DbConnection,
         nwc_uri: NostrWalletConnectURI,
         user_pubkey: PublicKey,
+        master_key: &MasterKey,
+        date_created: NaiveDateTime,
     ) -> Result<Self, diesel::result::Error> {
         let db = Self {
             request_key: nwc_uri.public_key.to_hex(),
-            response_key: nwc_uri.secret.secret_bytes().to_hex(),
+            response_key: master_key.encrypt(&nwc_uri.secret.secret_bytes()),
             relay_url: nwc_uri.relay_url.to_string(),
             user_pubkey: user_pubkey.to_hex(),
-            date_created: chrono::Utc::now().naive_utc().to_string(),
+            date_created,
+            lud16: nwc_uri.lud16,
+            fallback_relays: encode_relays(&[]),
         };
 
         diesel::insert_into(user_nwc::table)
@@ -58,23 +109,20 @@ impl UserNwc {
         Ok(db)
     }
 
-    pub fn find_by_response_key(
-        conn: &mut SqliteConnection,
-        response_key: &XOnlyPublicKey,
-    ) -> Result<Option<Self>, diesel::result::Error> {
-        let result = user_nwc::table
-            .filter(user_nwc::response_key.eq(response_key.to_hex()))
-            .first::<Self>(conn);
-
-        match result {
-            Ok(found) => Ok(Some(found)),
-            Err(diesel::NotFound) => Ok(None),
-            Err(e) => Err(e),
-        }
+    pub fn set_fallback_relays(
+        conn: &mut DbConnection,
+        request_key: &XOnlyPublicKey,
+        relays: &[Url],
+    ) -> Result<(), diesel::result::Error> {
+        diesel::update(user_nwc::table.find(request_key.to_hex()))
+            .set(user_nwc::fallback_relays.eq(encode_relays(relays)))
+            .execute(conn)?;
+
+        Ok(())
     }
 
     pub fn find_by_user(
-        conn: &mut SqliteConnection,
+        conn: &mut DbConnection,
         user_pubkey: &PublicKey,
     ) -> Result<Vec<Self>, diesel::result::Error> {
         let found = user_nwc::table
@@ -84,17 +132,47 @@ impl UserNwc {
         Ok(found)
     }
 
-    pub fn get_relays(conn: &mut SqliteConnection) -> Result<Vec<String>, diesel::result::Error> {
+    pub fn find_by_request_key(
+        conn: &mut DbConnection,
+        request_key: &XOnlyPublicKey,
+    ) -> Result<Option<Self>, diesel::result::Error> {
+        user_nwc::table
+            .find(request_key.to_hex())
+            .first::<Self>(conn)
+            .optional()
+    }
+
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Self>, diesel::result::Error> {
+        user_nwc::table
+            .order(user_nwc::date_created)
+            .load::<Self>(conn)
+    }
+
+    pub fn count(conn: &mut DbConnection) -> Result<i64, diesel::result::Error> {
+        user_nwc::table.count().get_result(conn)
+    }
+
+    /// Every relay a user's wallet may be reached on, fallbacks included
+    pub fn get_relays(conn: &mut DbConnection) -> Result<Vec<String>, diesel::result::Error> {
         let found = user_nwc::table
-            .select(user_nwc::relay_url)
-            .distinct()
-            .load::<String>(conn)?;
+            .select((user_nwc::relay_url, user_nwc::fallback_relays))
+            .load::<(String, String)>(conn)?;
+
+        let mut relays = vec![];
+        for (relay_url, fallback_relays) in found {
+            relays.push(relay_url);
+            let fallbacks: Vec<String> = serde_json::from_str(&fallback_relays)
+                .map_err(|e| DeserializationError(Box::new(e)))?;
+            relays.extend(fallbacks);
+        }
+        relays.sort();
+        relays.dedup();
 
-        Ok(found)
+        Ok(relays)
     }
 
     pub fn get_all_keys(
-        conn: &mut SqliteConnection,
+        conn: &mut DbConnection,
     ) -> Result<Vec<XOnlyPublicKey>, diesel::result::Error> {
         let found = user_nwc::table
             .select(user_nwc::request_key)
@@ -109,4 +187,31 @@ impl UserNwc {
 
         Ok(keys)
     }
+
+    /// Encrypts any response keys that are still stored as plaintext hex.
+    /// Returns the number of rows that were migrated.
+    pub fn encrypt_plaintext_keys(
+        conn: &mut DbConnection,
+        master_key: &MasterKey,
+    ) -> Result<usize, diesel::result::Error> {
+        let found = user_nwc::table
+            .select((user_nwc::request_key, user_nwc::response_key))
+            .load::<(String, String)>(conn)?;
+
+        let mut count = 0;
+        for (request_key, response_key) in found {
+            if let Ok(secret) = SecretKey::from_str(&response_key) {
+                diesel::update(user_nwc::table.find(request_key))
+                    .set(user_nwc::response_key.eq(master_key.encrypt(&secret.secret_bytes())))
+                    .execute(conn)?;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+fn encode_relays(relays: &[Url]) -> String {
+    serde_json::to_string(relays).expect("relays are serializable")
 }
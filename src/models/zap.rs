@@ -0,0 +1,127 @@
+use std::str::FromStr;
+
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::secp256k1::PublicKey;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use nostr::EventId;
+use serde::{Deserialize, Serialize};
+
+use super::schema::zaps;
+use super::DbConnection;
+
+/// An invoice made for a zap request, a receipt is published once it is paid
+#[derive(Queryable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(primary_key(payment_hash))]
+#[diesel(treat_none_as_default_value = false)]
+#[diesel(table_name = zaps)]
+pub struct Zap {
+    payment_hash: String,
+    user_pubkey: String,
+    invoice: String,
+    /// The zap request event as the sender gave it, the invoice commits to its hash
+    request: String,
+    receipt_id: Option<String>,
+    expires_at: NaiveDateTime,
+    date_created: NaiveDateTime,
+}
+
+impl Zap {
+    pub fn new(
+        payment_hash: String,
+        user_pubkey: PublicKey,
+        invoice: String,
+        request: String,
+        expires_at: NaiveDateTime,
+    ) -> Self {
+        Zap {
+            payment_hash,
+            user_pubkey: user_pubkey.to_hex(),
+            invoice,
+            request,
+            receipt_id: None,
+            expires_at,
+            date_created: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn payment_hash(&self) -> &str {
+        &self.payment_hash
+    }
+
+    pub fn user_pubkey(&self) -> PublicKey {
+        PublicKey::from_str(&self.user_pubkey).expect("invalid pubkey")
+    }
+
+    pub fn invoice(&self) -> &str {
+        &self.invoice
+    }
+
+    pub fn request(&self) -> &str {
+        &self.request
+    }
+
+    pub fn receipt_id(&self) -> Option<EventId> {
+        self.receipt_id
+            .as_deref()
+            .map(|id| EventId::from_hex(id).expect("invalid receipt id"))
+    }
+
+    pub fn expires_at(&self) -> NaiveDateTime {
+        self.expires_at
+    }
+
+    pub fn insert(conn: &mut DbConnection, zap: &Self) -> Result<(), diesel::result::Error> {
+        diesel::insert_into(zaps::table).values(zap).execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn find(
+        conn: &mut DbConnection,
+        payment_hash: &str,
+    ) -> Result<Option<Self>, diesel::result::Error> {
+        zaps::table
+            .find(payment_hash)
+            .first::<Self>(conn)
+            .optional()
+    }
+
+    /// Zaps without a receipt whose invoice expires after `expired_after`
+    pub fn get_pending(
+        conn: &mut DbConnection,
+        expired_after: NaiveDateTime,
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        zaps::table
+            .filter(zaps::receipt_id.is_null())
+            .filter(zaps::expires_at.gt(expired_after))
+            .order(zaps::date_created)
+            .load::<Self>(conn)
+    }
+
+    /// Number of a user's zaps still waiting to be paid
+    pub fn count_pending(
+        conn: &mut DbConnection,
+        user_pubkey: &PublicKey,
+        now: NaiveDateTime,
+    ) -> Result<i64, diesel::result::Error> {
+        zaps::table
+            .filter(zaps::user_pubkey.eq(user_pubkey.to_hex()))
+            .filter(zaps::receipt_id.is_null())
+            .filter(zaps::expires_at.gt(now))
+            .count()
+            .get_result(conn)
+    }
+
+    pub fn set_receipt(
+        conn: &mut DbConnection,
+        payment_hash: &str,
+        receipt_id: &EventId,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::update(zaps::table.find(payment_hash))
+            .set(zaps::receipt_id.eq(receipt_id.to_hex()))
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,405 @@
+//! NIP-47 messages, the nostr crate only knows a few of the methods so we parse requests ourselves
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::PublicKey;
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription};
+use nostr::nips::nip47::{ErrorCode, NIP47Error};
+use nostr::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::models::payment::Payment;
+
+/// Keysend TLV records below this are part of the lightning protocol, services can only set custom records
+const MIN_CUSTOM_TLV_TYPE: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NwcMethod {
+    PayInvoice,
+    MultiPayInvoice,
+    PayKeysend,
+    ListTransactions,
+    MakeInvoice,
+    LookupInvoice,
+    GetBalance,
+}
+
+impl NwcMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NwcMethod::PayInvoice => "pay_invoice",
+            NwcMethod::MultiPayInvoice => "multi_pay_invoice",
+            NwcMethod::PayKeysend => "pay_keysend",
+            NwcMethod::ListTransactions => "list_transactions",
+            NwcMethod::MakeInvoice => "make_invoice",
+            NwcMethod::LookupInvoice => "lookup_invoice",
+            NwcMethod::GetBalance => "get_balance",
+        }
+    }
+}
+
+impl FromStr for NwcMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pay_invoice" => Ok(NwcMethod::PayInvoice),
+            "multi_pay_invoice" => Ok(NwcMethod::MultiPayInvoice),
+            "pay_keysend" => Ok(NwcMethod::PayKeysend),
+            "list_transactions" => Ok(NwcMethod::ListTransactions),
+            "make_invoice" => Ok(NwcMethod::MakeInvoice),
+            "lookup_invoice" => Ok(NwcMethod::LookupInvoice),
+            "get_balance" => Ok(NwcMethod::GetBalance),
+            _ => Err(anyhow!("Unknown method {s}")),
+        }
+    }
+}
+
+impl fmt::Display for NwcMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A request with its params left as json until we know the method
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NwcRequest {
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+impl NwcRequest {
+    pub fn new(method: NwcMethod, params: impl Serialize) -> Self {
+        Self {
+            method: method.to_string(),
+            params: serde_json::to_value(params).expect("params are serializable"),
+        }
+    }
+
+    pub fn from_json(json: impl AsRef<str>) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(json.as_ref())?)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).expect("request is serializable")
+    }
+
+    pub fn params<T: for<'de> Deserialize<'de>>(&self) -> anyhow::Result<T> {
+        // treat missing params like empty ones, for methods where every param is optional
+        let params = match &self.params {
+            Value::Null => Value::Object(Default::default()),
+            params => params.clone(),
+        };
+        serde_json::from_value(params).map_err(|e| anyhow!("Invalid {} params: {e}", self.method))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayInvoiceParams {
+    pub invoice: String,
+    /// Amount in msats, only for invoices without an amount
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultiPayInvoiceParams {
+    pub invoices: Vec<MultiPayInvoiceItem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultiPayInvoiceItem {
+    /// Returned in the `d` tag of the response, defaults to the payment hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub invoice: String,
+    /// Amount in msats, only for invoices without an amount
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayKeysendParams {
+    /// Amount in msats
+    pub amount: u64,
+    /// Node pubkey of the destination
+    pub pubkey: PublicKey,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preimage: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tlv_records: Vec<TlvRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlvRecord {
+    #[serde(rename = "type")]
+    pub tlv_type: u64,
+    /// Hex encoded value
+    pub value: String,
+}
+
+impl PayKeysendParams {
+    /// Checks the preimage and TLV values are hex and custom records use the custom range
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(preimage) = &self.preimage {
+            let bytes: Vec<u8> =
+                FromHex::from_hex(preimage).map_err(|_| anyhow!("Preimage is not valid hex"))?;
+            if bytes.len() != 32 {
+                return Err(anyhow!("Preimage must be 32 bytes"));
+            }
+        }
+        for record in &self.tlv_records {
+            if record.tlv_type < MIN_CUSTOM_TLV_TYPE {
+                return Err(anyhow!("TLV type {} is reserved", record.tlv_type));
+            }
+            let _: Vec<u8> = FromHex::from_hex(&record.value)
+                .map_err(|_| anyhow!("TLV {} value is not valid hex", record.tlv_type))?;
+        }
+
+        Ok(())
+    }
+
+    /// Hash of the preimage, if the service picked one
+    pub fn payment_hash(&self) -> Option<String> {
+        let preimage: Vec<u8> = FromHex::from_hex(self.preimage.as_deref()?).ok()?;
+        Some(sha256::Hash::hash(&preimage).to_hex())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MakeInvoiceParams {
+    /// Amount in msats
+    pub amount: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description_hash: Option<String>,
+    /// Seconds until the invoice expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionType {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListTransactionsParams {
+    /// Unix timestamp, only transactions created at or after it
+    #[serde(default)]
+    pub from: Option<u64>,
+    /// Unix timestamp, only transactions created at or before it
+    #[serde(default)]
+    pub until: Option<u64>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+    #[serde(default)]
+    pub offset: Option<u64>,
+    /// Include payments that are pending or failed
+    #[serde(default)]
+    pub unpaid: bool,
+    #[serde(default, rename = "type")]
+    pub transaction_type: Option<TransactionType>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transaction {
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invoice: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preimage: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment_hash: Option<String>,
+    /// Amount in msats
+    pub amount: u64,
+    /// Fees in msats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fees_paid: Option<u64>,
+    pub created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settled_at: Option<i64>,
+}
+
+impl Transaction {
+    /// A payment made through the proxy, details missing from our records are read from the invoice
+    pub fn outgoing(payment: &Payment) -> Self {
+        let invoice = payment
+            .invoice()
+            .and_then(|invoice| Bolt11Invoice::from_str(invoice).ok());
+        let (description, description_hash) = match invoice.as_ref().map(|i| i.description()) {
+            Some(Bolt11InvoiceDescription::Direct(description)) => {
+                (Some(description.to_string()), None)
+            }
+            Some(Bolt11InvoiceDescription::Hash(hash)) => (None, Some(hash.0.to_string())),
+            None => (None, None),
+        };
+
+        Self {
+            transaction_type: TransactionType::Outgoing,
+            invoice: payment.invoice().map(String::from),
+            description,
+            description_hash,
+            preimage: payment.preimage().map(String::from),
+            payment_hash: payment.payment_hash().map(String::from),
+            amount: payment.amount_msats(),
+            fees_paid: payment.fees_paid_msats(),
+            created_at: payment.date_created().timestamp(),
+            expires_at: invoice
+                .and_then(|invoice| invoice.expires_at())
+                .map(|expires_at| expires_at.as_secs() as i64),
+            settled_at: payment.date_settled().map(|settled| settled.timestamp()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListTransactionsResult {
+    pub transactions: Vec<Transaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NwcResponse {
+    pub result_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<NIP47Error>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+}
+
+impl NwcResponse {
+    pub fn success(method: NwcMethod, result: impl Serialize) -> Self {
+        Self {
+            result_type: method.to_string(),
+            error: None,
+            result: Some(serde_json::to_value(result).expect("result is serializable")),
+        }
+    }
+
+    pub fn error(method: &str, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            result_type: method.to_string(),
+            error: Some(NIP47Error {
+                code,
+                message: message.into(),
+            }),
+            result: None,
+        }
+    }
+
+    pub fn from_json(json: impl AsRef<str>) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(json.as_ref())?)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).expect("response is serializable")
+    }
+}
+
+/// Every relay listed in a NWC uri, in order. NIP-47 lets wallets list several but the
+/// nostr crate only keeps one of them
+pub fn uri_relays(uri: &str) -> Vec<Url> {
+    let Ok(uri) = Url::parse(uri) else {
+        return vec![];
+    };
+    let mut relays: Vec<Url> = vec![];
+    for (key, value) in uri.query_pairs() {
+        if key != "relay" {
+            continue;
+        }
+        if let Ok(relay) = Url::parse(&value) {
+            if !relays.contains(&relay) {
+                relays.push(relay);
+            }
+        }
+    }
+    relays
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_multi_pay_invoice() {
+        let json = r#"{"method":"multi_pay_invoice","params":{"invoices":[{"id":"a","invoice":"lnbc1"},{"invoice":"lnbc2","amount":1000}]}}"#;
+        let req = NwcRequest::from_json(json).unwrap();
+        assert_eq!(
+            NwcMethod::from_str(&req.method).unwrap(),
+            NwcMethod::MultiPayInvoice
+        );
+
+        let params: MultiPayInvoiceParams = req.params().unwrap();
+        assert_eq!(params.invoices.len(), 2);
+        assert_eq!(params.invoices[0].id.as_deref(), Some("a"));
+        assert_eq!(params.invoices[1].amount, Some(1000));
+
+        // params can be left out when they're all optional
+        let req = NwcRequest::from_json(r#"{"method":"list_transactions"}"#).unwrap();
+        let params: ListTransactionsParams = req.params().unwrap();
+        assert_eq!(params, ListTransactionsParams::default());
+
+        // unknown methods still parse so we can answer them
+        let req = NwcRequest::from_json(r#"{"method":"sign_message","params":{}}"#).unwrap();
+        assert!(NwcMethod::from_str(&req.method).is_err());
+    }
+
+    #[test]
+    fn test_parse_pay_keysend() {
+        let json = r#"{"method":"pay_keysend","params":{"amount":21000,"pubkey":"03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad","tlv_records":[{"type":7629169,"value":"0102"}]}}"#;
+        let req = NwcRequest::from_json(json).unwrap();
+        let params: PayKeysendParams = req.params().unwrap();
+        assert_eq!(params.amount, 21_000);
+        assert_eq!(params.tlv_records[0].tlv_type, 7629169);
+        assert!(params.validate().is_ok());
+        assert_eq!(params.payment_hash(), None);
+
+        let mut invalid = params.clone();
+        invalid.tlv_records[0].tlv_type = 8;
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = params.clone();
+        invalid.tlv_records[0].value = "zz".to_string();
+        assert!(invalid.validate().is_err());
+
+        let mut with_preimage = params;
+        with_preimage.preimage = Some("00".repeat(32));
+        assert!(with_preimage.validate().is_ok());
+        assert_eq!(
+            with_preimage.payment_hash().as_deref(),
+            Some("66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925")
+        );
+        with_preimage.preimage = Some("00".to_string());
+        assert!(with_preimage.validate().is_err());
+    }
+
+    #[test]
+    fn test_uri_relays() {
+        let uri = "nostr+walletconnect://5fa11a95186e2bdc05e047d8573721b407aaa54e5c39f93b2811f176a65ac5f8?relay=wss%3A%2F%2Frelay.one&secret=e0d196bf4af30401332085702d35ec0c0b6d6bcc43b76d05d9d9898b2c2c6d94&relay=wss%3A%2F%2Frelay.two&relay=wss%3A%2F%2Frelay.one&relay=nope";
+        let relays = uri_relays(uri);
+        assert_eq!(
+            relays,
+            vec![
+                Url::parse("wss://relay.one").unwrap(),
+                Url::parse("wss://relay.two").unwrap()
+            ]
+        );
+        assert!(uri_relays("not a uri").is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+//! Connection health of the relays the subscriber uses. nostr-sdk retries a dropped relay on a
+//! fixed interval and gives up on it for good once it is stopped, so the subscriber checks each
+//! relay on its heartbeat and replaces the ones that stay down, backing off between tries.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDateTime;
+use nostr::Url;
+use nostr_sdk::RelayStatus;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Wait before reconnecting a relay that went down, doubled after each try that fails
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+struct Health {
+    status: RelayStatus,
+    connected_since: Option<NaiveDateTime>,
+    /// Round trip of the last event the relay confirmed
+    latency: Option<Duration>,
+    errors: u64,
+    last_error: Option<String>,
+    disconnects: u64,
+    /// Reconnects tried since the relay was last connected
+    reconnect_attempts: u32,
+    /// When to next reconnect, set while the relay is down
+    next_reconnect: Option<Instant>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            status: RelayStatus::Initialized,
+            connected_since: None,
+            latency: None,
+            errors: 0,
+            last_error: None,
+            disconnects: 0,
+            reconnect_attempts: 0,
+            next_reconnect: None,
+        }
+    }
+}
+
+/// A relay's health as shown on the admin endpoint
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayReport {
+    pub url: String,
+    pub status: String,
+    pub connected_since: Option<NaiveDateTime>,
+    /// Round trip of the last event the relay confirmed
+    pub latency_ms: Option<u64>,
+    pub errors: u64,
+    pub last_error: Option<String>,
+    pub disconnects: u64,
+    pub reconnect_attempts: u32,
+}
+
+/// Health of every relay the subscriber is using, cheap to clone
+#[derive(Clone, Default)]
+pub struct RelayHealth {
+    relays: Arc<Mutex<HashMap<Url, Health>>>,
+}
+
+impl RelayHealth {
+    /// Records a relay's current status, returns true if it has been down long enough that it
+    /// should be reconnected now
+    pub fn observe(&self, url: &Url, status: RelayStatus, now: Instant) -> bool {
+        let mut relays = self.relays.lock().unwrap();
+        let health = relays.entry(url.clone()).or_default();
+        let was_connected = health.status == RelayStatus::Connected;
+        health.status = status.clone();
+
+        match status {
+            RelayStatus::Connected => {
+                if !was_connected {
+                    if health.reconnect_attempts > 0 {
+                        info!(
+                            relay = %url,
+                            attempts = health.reconnect_attempts,
+                            "Relay reconnected"
+                        );
+                    }
+                    health.connected_since = Some(chrono::Utc::now().naive_utc());
+                    health.reconnect_attempts = 0;
+                    health.next_reconnect = None;
+                }
+                false
+            }
+            // not connected yet, either way it gets the same grace period as a dropped relay
+            RelayStatus::Initialized
+            | RelayStatus::Connecting
+            | RelayStatus::Disconnected
+            | RelayStatus::Stopped
+            | RelayStatus::Terminated => {
+                if was_connected {
+                    warn!(relay = %url, %status, "Relay disconnected");
+                    health.disconnects += 1;
+                    health.connected_since = None;
+                }
+                match health.next_reconnect {
+                    Some(at) if at <= now => {
+                        health.reconnect_attempts += 1;
+                        let backoff = reconnect_backoff(health.reconnect_attempts);
+                        health.next_reconnect = Some(now + backoff);
+                        info!(
+                            relay = %url,
+                            attempt = health.reconnect_attempts,
+                            next_in = ?backoff,
+                            "Reconnecting to relay"
+                        );
+                        true
+                    }
+                    Some(_) => false,
+                    None => {
+                        health.next_reconnect = Some(now + reconnect_backoff(0));
+                        false
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn record_error(&self, url: &Url, error: impl ToString) {
+        let mut relays = self.relays.lock().unwrap();
+        let health = relays.entry(url.clone()).or_default();
+        health.errors += 1;
+        health.last_error = Some(error.to_string());
+    }
+
+    pub fn record_latency(&self, url: &Url, latency: Duration) {
+        let mut relays = self.relays.lock().unwrap();
+        relays.entry(url.clone()).or_default().latency = Some(latency);
+    }
+
+    /// Called when the subscriber makes a new connection to every relay, so the relays coming
+    /// back up aren't taken for drops. Relays no longer in use are forgotten.
+    pub fn restart(&self, urls: &[Url]) {
+        let mut relays = self.relays.lock().unwrap();
+        relays.retain(|url, _| urls.contains(url));
+        for health in relays.values_mut() {
+            health.status = RelayStatus::Initialized;
+            health.connected_since = None;
+            health.reconnect_attempts = 0;
+            health.next_reconnect = None;
+        }
+    }
+
+    /// Every relay's health, sorted by url
+    pub fn report(&self) -> Vec<RelayReport> {
+        let relays = self.relays.lock().unwrap();
+        let mut report: Vec<RelayReport> = relays
+            .iter()
+            .map(|(url, health)| RelayReport {
+                url: url.to_string(),
+                status: health.status.to_string(),
+                connected_since: health.connected_since,
+                latency_ms: health.latency.map(|latency| latency.as_millis() as u64),
+                errors: health.errors,
+                last_error: health.last_error.clone(),
+                disconnects: health.disconnects,
+                reconnect_attempts: health.reconnect_attempts,
+            })
+            .collect();
+        report.sort_by(|a, b| a.url.cmp(&b.url));
+        report
+    }
+}
+
+/// Wait after the given number of failed reconnects
+fn reconnect_backoff(attempts: u32) -> Duration {
+    RECONNECT_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts))
+        .min(MAX_RECONNECT_BACKOFF)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let health = RelayHealth::default();
+        let url = Url::parse("wss://relay.example.com").unwrap();
+        let start = Instant::now();
+
+        assert!(!health.observe(&url, RelayStatus::Connecting, start));
+        assert!(!health.observe(&url, RelayStatus::Connected, start));
+        let report = &health.report()[0];
+        assert_eq!(report.status, "Connected");
+        assert!(report.connected_since.is_some());
+
+        // dropped, reconnects once the backoff has passed and backs off further each time
+        assert!(!health.observe(&url, RelayStatus::Disconnected, start));
+        assert_eq!(health.report()[0].disconnects, 1);
+        assert!(health.report()[0].connected_since.is_none());
+        assert!(!health.observe(
+            &url,
+            RelayStatus::Disconnected,
+            start + RECONNECT_BACKOFF / 2
+        ));
+        let first = start + RECONNECT_BACKOFF;
+        assert!(health.observe(&url, RelayStatus::Disconnected, first));
+        assert!(!health.observe(&url, RelayStatus::Connecting, first + RECONNECT_BACKOFF));
+        assert!(health.observe(
+            &url,
+            RelayStatus::Disconnected,
+            first + RECONNECT_BACKOFF * 2
+        ));
+        assert_eq!(health.report()[0].reconnect_attempts, 2);
+
+        // once connected again the backoff starts over
+        assert!(!health.observe(&url, RelayStatus::Connected, first + RECONNECT_BACKOFF * 3));
+        assert_eq!(health.report()[0].reconnect_attempts, 0);
+        assert_eq!(health.report()[0].disconnects, 1);
+
+        assert_eq!(reconnect_backoff(1), RECONNECT_BACKOFF * 2);
+        assert_eq!(reconnect_backoff(100), MAX_RECONNECT_BACKOFF);
+
+        health.record_error(&url, "blocked: no thanks");
+        health.record_latency(&url, Duration::from_millis(42));
+        let report = &health.report()[0];
+        assert_eq!(report.errors, 1);
+        assert_eq!(report.last_error.as_deref(), Some("blocked: no thanks"));
+        assert_eq!(report.latency_ms, Some(42));
+
+        // a new connection isn't a drop, and relays no longer used are forgotten
+        health.restart(std::slice::from_ref(&url));
+        assert!(!health.observe(&url, RelayStatus::Connecting, start));
+        assert_eq!(health.report()[0].disconnects, 1);
+        health.restart(&[]);
+        assert!(health.report().is_empty());
+    }
+}
@@ -1,13 +1,22 @@
-use crate::models::service_nwc::ServiceNwc;
-use crate::models::user::User;
+use crate::lnurl::lightning_address;
+use crate::models::service_nwc::{ServiceMetadata, ServiceNwc, SpendingLimits};
+use crate::models::user::{validate_lud16, validate_username, User};
 use crate::models::user_nwc::UserNwc;
+use crate::nwc::uri_relays;
+use crate::relay_health::RelayReport;
 use crate::State;
 use axum::http::StatusCode;
 use axum::{Extension, Json};
 use bitcoin::secp256k1::PublicKey;
+use chrono::NaiveDateTime;
+use nostr::key::XOnlyPublicKey;
 use nostr::nips::nip47::NostrWalletConnectURI;
+use nostr_sdk::RelayStatus;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::time::Duration;
+use tracing::{info, warn};
 
 pub(crate) fn handle_anyhow_error(err: anyhow::Error) -> (StatusCode, String) {
     (StatusCode::BAD_REQUEST, format!("{err}"))
@@ -17,6 +26,9 @@ pub(crate) fn handle_anyhow_error(err: anyhow::Error) -> (StatusCode, String) {
 pub struct SetUserNwcRequest {
     pub user_pubkey: PublicKey, // todo use actual auth
     nwc: String,
+    /// Lightning address to put in service uris, instead of the one in the wallet's uri
+    #[serde(default)]
+    lud16: Option<String>,
 }
 
 impl SetUserNwcRequest {
@@ -27,12 +39,34 @@ impl SetUserNwcRequest {
 
 pub(crate) fn set_user_nwc_impl(payload: SetUserNwcRequest, state: &State) -> anyhow::Result<()> {
     match payload.nwc() {
-        Some(nwc) => {
+        Some(mut nwc) => {
+            if let Some(lud16) = &payload.lud16 {
+                validate_lud16(lud16)?;
+            }
+            if let Some(lud16) = nwc.lud16.take() {
+                match validate_lud16(&lud16) {
+                    Ok(()) => nwc.lud16 = Some(lud16),
+                    Err(e) => warn!("Ignoring lightning address from wallet: {e}"),
+                }
+            }
+
+            // the first relay listed is the wallet's main one, the rest are fallbacks
+            let mut relays = uri_relays(&payload.nwc);
+            if !relays.is_empty() {
+                nwc.relay_url = relays.remove(0);
+            }
+
             let conn = &mut state.db_pool.get()?;
             let _ = User::create(conn, payload.user_pubkey)?;
-            let _ = UserNwc::create(conn, nwc.clone(), payload.user_pubkey)?;
+            let _ = UserNwc::create(conn, nwc.clone(), payload.user_pubkey, &state.master_key)?;
+            if !relays.is_empty() {
+                UserNwc::set_fallback_relays(conn, &nwc.public_key, &relays)?;
+            }
+            if let Some(lud16) = &payload.lud16 {
+                User::set_lud16(conn, &payload.user_pubkey, Some(lud16))?;
+            }
 
-            println!("New user: {}!", payload.user_pubkey);
+            info!(user_pubkey = %payload.user_pubkey, "New user nwc");
             // notify new key
             let keys = state.pubkeys.lock().unwrap();
             keys.send_if_modified(|current| {
@@ -64,21 +98,50 @@ pub async fn set_user_nwc(
 pub struct GetServiceNwcRequest {
     pub user_pubkey: PublicKey, // todo use actual auth
     service_name: String,
+    /// Unix timestamp after which the connection stops working
+    #[serde(default)]
+    expires_at: Option<i64>,
+    #[serde(flatten)]
+    metadata: ServiceMetadata,
+    #[serde(flatten)]
+    limits: SpendingLimits,
 }
 
 pub(crate) fn get_service_nwc_impl(
-    user_pubkey: PublicKey,
-    service_name: String,
+    payload: GetServiceNwcRequest,
     state: &State,
 ) -> anyhow::Result<NostrWalletConnectURI> {
-    let service_nwc = ServiceNwc::generate(user_pubkey, service_name);
+    payload.metadata.validate()?;
+    payload.limits.validate()?;
+    let expires_at = match payload.expires_at {
+        Some(timestamp) => match NaiveDateTime::from_timestamp_opt(timestamp, 0) {
+            Some(expires_at) if expires_at > chrono::Utc::now().naive_utc() => Some(expires_at),
+            _ => return Err(anyhow::anyhow!("expires_at must be in the future")),
+        },
+        None => None,
+    };
+
+    let service_nwc = ServiceNwc::generate(
+        payload.user_pubkey,
+        payload.service_name,
+        &state.config.service_relay,
+        &state.master_key,
+    )
+    .with_expiry(expires_at)
+    .with_metadata(&payload.metadata)
+    .with_limits(&payload.limits);
     let conn = &mut state.db_pool.get()?;
     ServiceNwc::insert(conn, &service_nwc)?;
+    let lud16 = User::lightning_address(conn, &service_nwc.user_pubkey())?;
 
-    println!("New service nwc: {}!", service_nwc.user_pubkey());
+    info!(
+        user_pubkey = %service_nwc.user_pubkey(),
+        service_key = %service_nwc.request_key(),
+        "New service nwc"
+    );
     // notify new key
     let keys = state.pubkeys.lock().unwrap();
-    let nwc = service_nwc.nwc_uri();
+    let nwc = service_nwc.nwc_uri(&state.master_key, lud16)?;
     keys.send_if_modified(|current| {
         if current.contains(&nwc.public_key) {
             false
@@ -95,8 +158,173 @@ pub async fn get_service_nwc(
     Extension(state): Extension<State>,
     Json(payload): Json<GetServiceNwcRequest>,
 ) -> Result<Json<String>, (StatusCode, String)> {
-    match get_service_nwc_impl(payload.user_pubkey, payload.service_name, &state) {
+    match get_service_nwc_impl(payload, &state) {
         Ok(nwc) => Ok(Json(nwc.to_string())),
         Err(e) => Err(handle_anyhow_error(e)),
     }
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateServiceNwcRequest {
+    pub user_pubkey: PublicKey, // todo use actual auth
+    /// Pubkey of the service connection, as in its NWC uri
+    request_key: XOnlyPublicKey,
+    /// Replaces all of the connection's metadata
+    #[serde(flatten)]
+    metadata: ServiceMetadata,
+}
+
+pub(crate) fn update_service_nwc_impl(
+    payload: UpdateServiceNwcRequest,
+    state: &State,
+) -> anyhow::Result<()> {
+    payload.metadata.validate()?;
+
+    let conn = &mut state.db_pool.get()?;
+    match ServiceNwc::find_by_request_key(conn, &payload.request_key)? {
+        Some(service_nwc) if service_nwc.user_pubkey() == payload.user_pubkey => {}
+        _ => return Err(anyhow::anyhow!("No service nwc found")),
+    }
+    ServiceNwc::update_metadata(conn, &payload.request_key, &payload.metadata)?;
+
+    info!(
+        user_pubkey = %payload.user_pubkey,
+        service_key = %payload.request_key,
+        "Updated service nwc"
+    );
+
+    Ok(())
+}
+
+pub async fn update_service_nwc(
+    Extension(state): Extension<State>,
+    Json(payload): Json<UpdateServiceNwcRequest>,
+) -> Result<Json<()>, (StatusCode, String)> {
+    match update_service_nwc_impl(payload, &state) {
+        Ok(_) => Ok(Json(())),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterUsernameRequest {
+    pub user_pubkey: PublicKey, // todo use actual auth
+    /// Replaces any username the user already has
+    username: String,
+}
+
+/// Registers the name of the user's lightning address on the proxy, returns the address
+pub(crate) fn register_username_impl(
+    payload: RegisterUsernameRequest,
+    state: &State,
+) -> anyhow::Result<String> {
+    let Some(public_url) = &state.config.public_url else {
+        return Err(anyhow::anyhow!("Lightning addresses are not enabled"));
+    };
+    validate_username(&payload.username)?;
+
+    let conn = &mut state.db_pool.get()?;
+    if User::find(conn, &payload.user_pubkey)?.is_none() {
+        return Err(anyhow::anyhow!("No user found"));
+    }
+    match User::find_by_username(conn, &payload.username)? {
+        Some(user) if user.pubkey() != payload.user_pubkey => {
+            return Err(anyhow::anyhow!("Username is taken"))
+        }
+        _ => {}
+    }
+    User::set_username(conn, &payload.user_pubkey, Some(&payload.username))
+        .map_err(|_| anyhow::anyhow!("Username is taken"))?;
+
+    info!(
+        user_pubkey = %payload.user_pubkey,
+        username = payload.username,
+        "Registered username"
+    );
+
+    Ok(lightning_address(public_url, &payload.username))
+}
+
+pub async fn register_username(
+    Extension(state): Extension<State>,
+    Json(payload): Json<RegisterUsernameRequest>,
+) -> Result<Json<String>, (StatusCode, String)> {
+    match register_username_impl(payload, &state) {
+        Ok(address) => Ok(Json(address)),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+pub async fn metrics(Extension(state): Extension<State>) -> Result<String, (StatusCode, String)> {
+    state
+        .metrics
+        .encode()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}")))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthResponse {
+    pub healthy: bool,
+    pub database: bool,
+    pub subscriber: bool,
+    pub relays: HashMap<String, String>,
+    pub connected_relays: usize,
+    pub watched_keys: usize,
+}
+
+async fn health_report(state: &State) -> HealthResponse {
+    // waiting on a busy pool blocks, keep it off the runtime's worker threads
+    let db_pool = state.db_pool.clone();
+    let database =
+        tokio::task::spawn_blocking(move || db_pool.get_timeout(Duration::from_secs(5)).is_ok())
+            .await
+            .unwrap_or(false);
+    let subscriber = state.subscriber.is_alive();
+    let statuses = state.subscriber.relay_statuses().await;
+    let connected_relays = statuses
+        .values()
+        .filter(|s| **s == RelayStatus::Connected)
+        .count();
+    let relays = statuses
+        .into_iter()
+        .map(|(url, status)| (url, status.to_string()))
+        .collect();
+    let watched_keys = state.pubkeys.lock().unwrap().borrow().len();
+
+    HealthResponse {
+        healthy: database && subscriber,
+        database,
+        subscriber,
+        relays,
+        connected_relays,
+        watched_keys,
+    }
+}
+
+/// Liveness check, fails if the database is unreachable or the subscriber is wedged
+pub async fn health(Extension(state): Extension<State>) -> (StatusCode, Json<HealthResponse>) {
+    let report = health_report(&state).await;
+    let code = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(report))
+}
+
+/// Connection state, latency and errors of each relay the subscriber uses
+pub async fn relays(Extension(state): Extension<State>) -> Json<Vec<RelayReport>> {
+    Json(state.subscriber.relay_report())
+}
+
+/// Readiness check, additionally requires at least one connected relay
+pub async fn ready(Extension(state): Extension<State>) -> (StatusCode, Json<HealthResponse>) {
+    let mut report = health_report(&state).await;
+    report.healthy = report.healthy && report.connected_relays > 0;
+    let code = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(report))
+}
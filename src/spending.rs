@@ -0,0 +1,210 @@
+use bitcoin::secp256k1::PublicKey;
+use chrono::NaiveDateTime;
+use nostr::nips::nip47::ErrorCode;
+
+use crate::config::PolicyConfig;
+use crate::models::service_nwc::{BudgetRenewal, SpendingLimits};
+
+/// Largest amount that can be paid or used as a limit, amounts are stored as signed 64 bit msats
+pub const MAX_AMOUNT_MSATS: u64 = i64::MAX as u64;
+
+/// Limits that apply to a service connection's payments, after falling back to the configured defaults
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendingPolicy {
+    pub max_payment_msats: Option<u64>,
+    pub budget_msats: Option<u64>,
+    pub budget_renewal: BudgetRenewal,
+    pub blocked_payees: Vec<PublicKey>,
+}
+
+/// Why a payment was not allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyViolation {
+    /// The amount is not known so it can't be checked against the limits
+    UnknownAmount,
+    OverPaymentCap,
+    OverBudget,
+    BlockedPayee,
+}
+
+impl PolicyViolation {
+    /// Label for the rejected requests metric
+    pub fn reason(&self) -> &'static str {
+        match self {
+            PolicyViolation::UnknownAmount => "unknown_amount",
+            PolicyViolation::OverPaymentCap => "over_payment_cap",
+            PolicyViolation::OverBudget => "over_budget",
+            PolicyViolation::BlockedPayee => "blocked_payee",
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            PolicyViolation::UnknownAmount => ErrorCode::Other,
+            PolicyViolation::OverPaymentCap => ErrorCode::Restricted,
+            PolicyViolation::OverBudget => ErrorCode::QuotaExceeded,
+            PolicyViolation::BlockedPayee => ErrorCode::Restricted,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            PolicyViolation::UnknownAmount => "Payment amount is required",
+            PolicyViolation::OverPaymentCap => "Payment is over the maximum payment amount",
+            PolicyViolation::OverBudget => "Payment would exceed the connection's budget",
+            PolicyViolation::BlockedPayee => "Payments to this node are not allowed",
+        }
+    }
+}
+
+impl SpendingPolicy {
+    pub fn new(limits: SpendingLimits, defaults: &PolicyConfig) -> Self {
+        let max_payment_sats = limits.max_payment_sats.or(defaults.max_payment_sats);
+        let budget_sats = limits.budget_sats.or(defaults.budget_sats);
+        let budget_renewal = limits
+            .budget_renewal
+            .or(defaults.budget_renewal)
+            .unwrap_or_default();
+
+        Self {
+            // limits are validated when they're set, a larger one can't be exceeded anyway
+            max_payment_msats: max_payment_sats.map(|sats| sats.saturating_mul(1_000)),
+            budget_msats: budget_sats.map(|sats| sats.saturating_mul(1_000)),
+            budget_renewal,
+            blocked_payees: defaults.blocked_payees.clone(),
+        }
+    }
+
+    /// Payments made since this time count against the budget
+    pub fn budget_start(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        self.budget_renewal.period_start(now)
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_payment_msats.is_none() && self.budget_msats.is_none()
+    }
+
+    /// Checks a payment given how much was already spent in the current budget period
+    pub fn check(
+        &self,
+        spent_msats: u64,
+        amount_msats: Option<u64>,
+    ) -> Result<(), PolicyViolation> {
+        if self.is_unlimited() {
+            return Ok(());
+        }
+
+        let Some(amount_msats) = amount_msats else {
+            return Err(PolicyViolation::UnknownAmount);
+        };
+        if amount_msats > MAX_AMOUNT_MSATS
+            || self.max_payment_msats.is_some_and(|max| amount_msats > max)
+        {
+            return Err(PolicyViolation::OverPaymentCap);
+        }
+        let over_budget = self.budget_msats.is_some_and(|budget| {
+            spent_msats
+                .checked_add(amount_msats)
+                .is_none_or(|total| total > budget)
+        });
+        if over_budget {
+            return Err(PolicyViolation::OverBudget);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the node being paid is allowed
+    pub fn check_payee(&self, payee: &PublicKey) -> Result<(), PolicyViolation> {
+        if self.blocked_payees.contains(payee) {
+            return Err(PolicyViolation::BlockedPayee);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    const BLOCKED_PAYEE: &str =
+        "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619";
+    const OTHER_PAYEE: &str = "03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad";
+
+    #[test]
+    fn test_spending_policy() {
+        let defaults = PolicyConfig {
+            max_payment_sats: Some(100),
+            budget_sats: Some(1_000),
+            budget_renewal: Some(BudgetRenewal::Daily),
+            blocked_payees: vec![PublicKey::from_str(BLOCKED_PAYEE).unwrap()],
+        };
+
+        let policy = SpendingPolicy::new(SpendingLimits::default(), &defaults);
+        assert_eq!(policy.check(0, Some(100_000)), Ok(()));
+        assert_eq!(
+            policy.check(0, Some(100_001)),
+            Err(PolicyViolation::OverPaymentCap)
+        );
+        assert_eq!(policy.check(900_000, Some(100_000)), Ok(()));
+        assert_eq!(
+            policy.check(900_001, Some(100_000)),
+            Err(PolicyViolation::OverBudget)
+        );
+        assert_eq!(policy.check(0, None), Err(PolicyViolation::UnknownAmount));
+        assert_eq!(
+            policy.check_payee(&PublicKey::from_str(BLOCKED_PAYEE).unwrap()),
+            Err(PolicyViolation::BlockedPayee)
+        );
+        assert_eq!(
+            policy.check_payee(&PublicKey::from_str(OTHER_PAYEE).unwrap()),
+            Ok(())
+        );
+
+        // the connection's own limits take precedence
+        let limits = SpendingLimits {
+            max_payment_sats: Some(1_000),
+            budget_sats: None,
+            budget_renewal: Some(BudgetRenewal::Never),
+        };
+        let policy = SpendingPolicy::new(limits, &defaults);
+        assert_eq!(policy.check(0, Some(1_000_000)), Ok(()));
+        assert_eq!(policy.budget_start(chrono::Utc::now().naive_utc()), None);
+
+        // amounts that would overflow are never allowed
+        assert_eq!(
+            policy.check(0, Some(u64::MAX)),
+            Err(PolicyViolation::OverPaymentCap)
+        );
+        let policy = SpendingPolicy::new(
+            SpendingLimits {
+                budget_sats: Some(MAX_AMOUNT_MSATS / 1_000),
+                ..Default::default()
+            },
+            &PolicyConfig::default(),
+        );
+        assert_eq!(
+            policy.check(u64::MAX - 10, Some(MAX_AMOUNT_MSATS)),
+            Err(PolicyViolation::OverBudget)
+        );
+        let policy = SpendingPolicy::new(
+            SpendingLimits {
+                max_payment_sats: Some(u64::MAX),
+                ..Default::default()
+            },
+            &PolicyConfig::default(),
+        );
+        assert_eq!(policy.max_payment_msats, Some(u64::MAX));
+        let too_large = SpendingLimits {
+            budget_sats: Some(MAX_AMOUNT_MSATS / 1_000 + 1),
+            ..Default::default()
+        };
+        assert!(too_large.validate().is_err());
+
+        // no limits at all
+        let policy = SpendingPolicy::new(SpendingLimits::default(), &PolicyConfig::default());
+        assert_eq!(policy.check(u64::MAX / 2, None), Ok(()));
+    }
+}
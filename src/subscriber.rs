@@ -1,35 +1,313 @@
-use crate::models::service_nwc::{ServiceNwc, DEFAULT_SERVICE_RELAY};
+use crate::metrics::Metrics;
+use crate::models::payment::{Payment, PaymentFilter, PaymentStatus};
+use crate::models::service_nwc::ServiceNwc;
 use crate::models::user_nwc::UserNwc;
+use crate::models::DbConnection;
+use crate::nwc::{
+    ListTransactionsParams, ListTransactionsResult, MultiPayInvoiceItem, MultiPayInvoiceParams,
+    NwcMethod, NwcRequest, NwcResponse, PayInvoiceParams, PayKeysendParams, Transaction,
+    TransactionType,
+};
+use crate::relay_health::{RelayHealth, RelayReport};
+use crate::spending::{SpendingPolicy, MAX_AMOUNT_MSATS};
+use crate::{identity, zaps, State};
 use anyhow::anyhow;
-use diesel::r2d2::{ConnectionManager, Pool};
-use diesel::SqliteConnection;
+use bitcoin::secp256k1::PublicKey;
+use chrono::NaiveDateTime;
+use diesel::Connection;
+use lightning_invoice::Bolt11Invoice;
 use nostr::key::XOnlyPublicKey;
-use nostr::nips::nip47::{Method, NostrWalletConnectURI, Request, RequestParams};
-use nostr::prelude::{decrypt, encrypt, PayInvoiceRequestParams, Secp256k1};
-use nostr::{Event, EventBuilder, Filter, Keys, Kind, Tag, Timestamp};
-use nostr_sdk::{Client, RelayPoolNotification};
-use std::time::Duration;
+use nostr::nips::nip47::{ErrorCode, NostrWalletConnectURI};
+use nostr::prelude::{decrypt, encrypt, Secp256k1};
+use nostr::{
+    ClientMessage, Event, EventBuilder, EventId, Filter, Keys, Kind, RelayMessage, Tag, Timestamp,
+    Url,
+};
+use nostr_sdk::relay::{self, pool};
+use nostr_sdk::{Client, RelayPoolNotification, RelaySendOptions, RelayStatus};
+use std::collections::HashMap;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::watch::Receiver;
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::JoinSet;
+use tracing::field::Empty;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
+/// Requests we forwarded to a user's wallet that are waiting on a response
+type PendingRequests = Arc<Mutex<HashMap<EventId, (NwcMethod, Instant)>>>;
+
+const PENDING_EXPIRY: Duration = Duration::from_secs(120);
+
+/// Rounds of attempts to publish an event to a connection's relays before giving up
+const PUBLISH_ATTEMPTS: u32 = 3;
+/// Wait after the first failed round, doubled after each one
+const PUBLISH_BACKOFF: Duration = Duration::from_millis(250);
+/// How long a relay gets to confirm an event with an OK message
+const PUBLISH_OK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Most time spent publishing one event, so handlers still answer within the request timeout
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most invoices in a single multi_pay_invoice request
+const MAX_MULTI_PAY_INVOICES: usize = 25;
+
+/// Most transactions returned for a single list_transactions request
+const MAX_TRANSACTIONS: u64 = 100;
+
+/// How long to remember the methods a wallet advertises
+const WALLET_INFO_TTL: Duration = Duration::from_secs(600);
+const WALLET_INFO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Methods a wallet advertised and when we fetched them
+type WalletInfo = (Vec<String>, Instant);
+
+/// Methods each user wallet advertises in its info event
+#[derive(Clone, Default)]
+struct WalletMethods {
+    cache: Arc<Mutex<HashMap<XOnlyPublicKey, WalletInfo>>>,
+}
+
+impl WalletMethods {
+    async fn supports(&self, client: &Client, wallet: XOnlyPublicKey, method: NwcMethod) -> bool {
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&wallet)
+            .filter(|(_, fetched_at)| fetched_at.elapsed() < WALLET_INFO_TTL)
+            .map(|(methods, _)| methods.clone());
+
+        let methods = match cached {
+            Some(methods) => methods,
+            None => {
+                let filter = Filter::new()
+                    .kind(Kind::WalletConnectInfo)
+                    .authors(vec![wallet.to_string()]);
+                match client
+                    .get_events_of(vec![filter], Some(WALLET_INFO_TIMEOUT))
+                    .await
+                {
+                    Ok(events) => {
+                        let methods: Vec<String> = events
+                            .iter()
+                            .max_by_key(|event| event.created_at)
+                            .map(|event| {
+                                event.content.split_whitespace().map(String::from).collect()
+                            })
+                            .unwrap_or_default();
+                        self.cache
+                            .lock()
+                            .unwrap()
+                            .insert(wallet, (methods.clone(), Instant::now()));
+                        methods
+                    }
+                    Err(e) => {
+                        warn!("Could not fetch wallet info: {e}");
+                        vec![]
+                    }
+                }
+            }
+        };
+
+        methods.iter().any(|m| m == method.as_str())
+    }
+}
+
+/// How often the subscriber loop checks in, if it misses a few of these it is considered dead
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often expired service connections are removed from the watched keys
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Requests the proxy made to a user's wallet itself, by event id, with the wallet expected to answer
+type WalletRequests = Arc<Mutex<HashMap<EventId, (XOnlyPublicKey, oneshot::Sender<Event>)>>>;
+
+/// Shared view of the subscriber task, used by the health endpoints and to make
+/// requests to user wallets outside of a service's request
+#[derive(Clone, Default)]
+pub struct SubscriberStatus {
+    client: Arc<RwLock<Option<Client>>>,
+    heartbeat: Arc<Mutex<Option<Instant>>>,
+    wallet_requests: WalletRequests,
+    relay_health: RelayHealth,
+}
+
+impl SubscriberStatus {
+    fn set_client(&self, client: Option<Client>) {
+        *self.client.write().unwrap() = client;
+    }
+
+    fn beat(&self) {
+        *self.heartbeat.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// If the subscriber loop has checked in recently
+    pub fn is_alive(&self) -> bool {
+        self.heartbeat
+            .lock()
+            .unwrap()
+            .is_some_and(|beat| beat.elapsed() < HEARTBEAT_TIMEOUT)
+    }
+
+    /// Connection status of each relay the current client is using
+    pub async fn relay_statuses(&self) -> HashMap<String, RelayStatus> {
+        let client = self.client.read().unwrap().clone();
+        let mut statuses = HashMap::new();
+        if let Some(client) = client {
+            for (url, relay) in client.relays().await {
+                statuses.insert(url.to_string(), relay.status().await);
+            }
+        }
+        statuses
+    }
+
+    /// Connection state, latency and errors of each relay
+    pub fn relay_report(&self) -> Vec<RelayReport> {
+        self.relay_health.report()
+    }
+
+    /// Sends a request to a user's wallet and waits for its response
+    pub async fn request_wallet(
+        &self,
+        state: &State,
+        user_nwc: &UserNwc,
+        req: &NwcRequest,
+        timeout: Duration,
+    ) -> anyhow::Result<NwcResponse> {
+        let client = self
+            .client
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow!("Not connected to relays"))?;
+
+        let nwc = user_nwc.nwc_uri(&state.master_key)?;
+        let event = create_nwc_request(&nwc, req);
+        let (tx, rx) = oneshot::channel();
+        self.wallet_requests
+            .lock()
+            .unwrap()
+            .insert(event.id, (nwc.public_key, tx));
+
+        let result = tokio::time::timeout(timeout, async {
+            publish(state, &client, &user_nwc.relays(), &event).await?;
+            let response = rx.await?;
+            let decrypted = decrypt(&nwc.secret, &response.pubkey, &response.content)?;
+            NwcResponse::from_json(decrypted)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Timed out waiting on wallet")));
+
+        self.wallet_requests.lock().unwrap().remove(&event.id);
+        result
+    }
+
+    /// Sends a direct message from the proxy's identity to a user, on the relays the
+    /// subscriber is connected to
+    pub async fn direct_message(
+        &self,
+        state: &State,
+        user_pubkey: &PublicKey,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        let client = self
+            .client
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow!("Not connected to relays"))?;
+
+        let receiver = user_pubkey.x_only_public_key().0;
+        let event = identity::direct_message(&state.identity, receiver, message)?;
+        client.send_event(event).await?;
+        Ok(())
+    }
+
+    /// Hands a wallet's response to the request waiting on it, returns false if nothing was waiting
+    fn resolve_wallet_request(&self, request_id: &EventId, event: &Event) -> bool {
+        let mut waiting = self.wallet_requests.lock().unwrap();
+        match waiting.get(request_id) {
+            Some((wallet, _)) if *wallet == event.pubkey => {
+                if let Some((_, tx)) = waiting.remove(request_id) {
+                    let _ = tx.send(event.clone());
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Listens for service requests and wallet responses until `shutdown` is set, then waits
+/// for in-flight requests to finish before disconnecting
 pub async fn start_subscription(
-    db_pool: Pool<ConnectionManager<SqliteConnection>>,
+    state: State,
     mut rx: Receiver<Vec<XOnlyPublicKey>>,
+    mut shutdown: Receiver<bool>,
 ) -> anyhow::Result<()> {
-    let keys = Keys::generate();
+    let State {
+        db_pool,
+        metrics,
+        subscriber: status,
+        config,
+        identity,
+        ..
+    } = state.clone();
+    let request_timeout = Duration::from_secs(config.request_timeout);
+    let pending: PendingRequests = Arc::new(Mutex::new(HashMap::new()));
+    let wallet_methods = WalletMethods::default();
+    let mut tasks = JoinSet::new();
+    // events beyond the workers wait in the queue, past that we stop reading from the relays
+    let workers = Arc::new(Semaphore::new(config.workers));
+    let queue_limit = config.workers + config.queue_size;
+
+    // pick up wallet responses sent while we were not running, to payments forwarded before a restart
+    let mut resume_since = {
+        let db = &mut db_pool.get()?;
+        let expiry = chrono::Duration::from_std(PENDING_EXPIRY)?;
+        Payment::oldest_pending(db, chrono::Utc::now().naive_utc() - expiry)?
+    };
     loop {
-        let client = Client::new(&keys);
+        status.beat();
+        let client = Client::new(&identity);
 
         let db_relays = {
             let db = &mut db_pool.get()?;
-            let relays = UserNwc::get_relays(db)?;
+            let mut relays = UserNwc::get_relays(db)?;
+            // responses go back on the relay each service connection was given
+            relays.extend(ServiceNwc::get_relays(db)?);
+            relays.sort();
+            relays.dedup();
 
             relays.into_iter().map(|r| (r, None)).collect::<Vec<_>>()
         };
         client.add_relays(db_relays).await?;
-        client.add_relay(DEFAULT_SERVICE_RELAY, None).await?;
+        client
+            .add_relay(config.service_relay.as_str(), None)
+            .await?;
+        for relay in &config.relays {
+            client.add_relay(relay.as_str(), None).await?;
+        }
         client.connect().await;
+        status.set_client(Some(client.clone()));
+        tokio::spawn({
+            let client = client.clone();
+            let metadata = identity::metadata_event(&identity, config.public_url.as_ref())?;
+            async move {
+                if let Err(e) = client.send_event(metadata).await {
+                    warn!("Could not publish the proxy's metadata: {e}");
+                }
+            }
+        });
+        let urls: Vec<Url> = client.relays().await.into_keys().collect();
+        status.relay_health.restart(&urls);
 
         let keys: Vec<XOnlyPublicKey> = rx.borrow().clone();
+        metrics.active_keys.set(keys.len() as i64);
         let authors: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
 
         let kinds = vec![Kind::WalletConnectRequest, Kind::WalletConnectResponse];
@@ -39,85 +317,425 @@ pub async fn start_subscription(
             .pubkeys(keys)
             .since(Timestamp::now());
 
+        // wallets' payment notifications tell us when zaps are paid
+        let mut wallet_kinds = kinds;
+        wallet_kinds.push(Kind::Custom(zaps::NOTIFICATION_KIND));
         let subscription2 = Filter::new()
-            .kinds(kinds)
-            .authors(authors)
+            .kinds(wallet_kinds)
+            .authors(authors.clone())
             .since(Timestamp::now());
 
-        client.subscribe(vec![subscription, subscription2]).await;
+        let mut filters = vec![subscription, subscription2];
+        if let Some(since) = resume_since.take() {
+            info!(%since, "Resuming wallet responses to pending payments");
+            filters.push(
+                Filter::new()
+                    .kind(Kind::WalletConnectResponse)
+                    .authors(authors)
+                    .since(Timestamp::from(since.timestamp() as u64)),
+            );
+        }
+        client.subscribe(filters).await;
 
-        println!("Listening for nwc events...");
+        info!(
+            keys = metrics.active_keys.get(),
+            "Listening for nwc events..."
+        );
 
         let mut notifications = client.notifications();
+        let mut relay_status_interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        // set once shutting down, when we stop waiting on in-flight requests
+        let mut drain_deadline: Option<tokio::time::Instant> = None;
+        let mut queue_full = false;
         loop {
+            // stop reading events while the queue is full, they wait in the notification channel
+            // and the other arms keep running
+            if queue_full != (tasks.len() >= queue_limit) {
+                queue_full = !queue_full;
+                if queue_full {
+                    warn!(queued = tasks.len(), "Event queue is full, waiting on workers");
+                }
+            }
             tokio::select! {
-                Ok(notification) = notifications.recv() => {
-                    if let RelayPoolNotification::Event(_url, event) = notification {
+                notification = notifications.recv(), if !queue_full => {
+                    let notification = match notification {
+                        Ok(notification) => notification,
+                        Err(RecvError::Lagged(missed)) => {
+                            metrics.events_dropped.inc_by(missed);
+                            warn!(missed, "Fell behind the relays, events were dropped");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    if let RelayPoolNotification::Message(url, RelayMessage::Auth { challenge }) = notification {
+                        if let Err(e) = authenticate(&client, &identity, url, challenge).await {
+                            error!("Error authenticating to relay: {e}");
+                        }
+                    } else if let RelayPoolNotification::Event(_url, event) = notification {
                         match event.kind {
+                            Kind::WalletConnectRequest if drain_deadline.is_some() => {
+                                info!(event_id = %event.id, "Ignoring request while shutting down");
+                            }
                             Kind::WalletConnectRequest => {
-                                println!("Received request");
-                                tokio::spawn({
-                                    let db_pool = db_pool.clone();
+                                let span = info_span!(
+                                    "request",
+                                    event_id = %event.id,
+                                    service_key = Empty,
+                                    user_pubkey = Empty,
+                                );
+                                enqueue(&mut tasks, &workers, &metrics, "request", {
+                                    let state = state.clone();
                                     let client = client.clone();
+                                    let pending = pending.clone();
+                                    let wallet_methods = wallet_methods.clone();
                                     async move {
-                                        let fut = handle_request(
-                                            db_pool,
-                                            &client,
-                                            event,
-                                        );
+                                        let deadline = tokio::time::Instant::now() + request_timeout;
+                                        let fut = handle_request(&state, &client, &pending, &wallet_methods, event, deadline);
 
-                                        match tokio::time::timeout(Duration::from_secs(30), fut).await {
+                                        match tokio::time::timeout_at(deadline, fut).await {
                                             Ok(Ok(_)) => {}
-                                            Ok(Err(e)) => eprintln!("Error: {e}"),
-                                            Err(_) => eprintln!("Timeout"),
+                                            Ok(Err(e)) => error!("Error handling request: {e}"),
+                                            Err(_) => {
+                                                state.metrics.timeouts.with_label_values(&["request"]).inc();
+                                                error!("Timeout handling request")
+                                            }
                                         }
                                     }
+                                    .instrument(span)
                                 });
                             }
                             Kind::WalletConnectResponse => {
-                                println!("Received response");
-                                 tokio::spawn({
-                                    let db_pool = db_pool.clone();
+                                let span = info_span!(
+                                    "response",
+                                    event_id = %event.id,
+                                    wallet_key = %event.pubkey,
+                                    request_id = Empty,
+                                );
+                                enqueue(&mut tasks, &workers, &metrics, "response", {
+                                    let state = state.clone();
                                     let client = client.clone();
+                                    let pending = pending.clone();
                                     async move {
-                                        let fut = handle_response(
-                                            db_pool,
-                                            &client,
-                                            event,
-                                        );
+                                        let fut = handle_response(&state, &client, &pending, event);
 
-                                        match tokio::time::timeout(Duration::from_secs(30), fut).await {
+                                        match tokio::time::timeout(request_timeout, fut).await {
                                             Ok(Ok(_)) => {}
-                                            Ok(Err(e)) => eprintln!("Error: {e}"),
-                                            Err(_) => eprintln!("Timeout"),
+                                            Ok(Err(e)) => error!("Error handling response: {e}"),
+                                            Err(_) => {
+                                                state.metrics.timeouts.with_label_values(&["response"]).inc();
+                                                error!("Timeout handling response")
+                                            }
+                                        }
+                                    }
+                                    .instrument(span)
+                                });
+                            }
+                            Kind::Custom(zaps::NOTIFICATION_KIND) => {
+                                let span = info_span!(
+                                    "notification",
+                                    event_id = %event.id,
+                                    wallet_key = %event.pubkey,
+                                );
+                                enqueue(&mut tasks, &workers, &metrics, "notification", {
+                                    let state = state.clone();
+                                    async move {
+                                        if let Err(e) = zaps::handle_notification(&state, event).await {
+                                            error!("Error handling notification: {e}");
                                         }
                                     }
+                                    .instrument(span)
                                 });
                             }
-                            kind => println!("Received event with invalid kind: {kind:?}")
+                            kind => warn!("Received event with invalid kind: {kind:?}")
                         }
                     }
                 }
-                _ = rx.changed() => {
+                _ = relay_status_interval.tick() => {
+                    status.beat();
+                    for (url, relay) in client.relays().await {
+                        let relay_status = relay.status().await;
+                        metrics.set_relay_status(url.as_str(), relay_status.clone());
+                        if status.relay_health.observe(&url, relay_status, Instant::now()) {
+                            metrics.relay_reconnects.with_label_values(&[url.as_str()]).inc();
+                            if let Err(e) = reconnect(&client, &url).await {
+                                error!(relay = %url, "Error reconnecting to relay: {e}");
+                                status.relay_health.record_error(&url, e);
+                            }
+                        }
+                    }
+                    // forget requests the wallet never answered
+                    pending
+                        .lock()
+                        .unwrap()
+                        .retain(|_, (_, sent_at)| sent_at.elapsed() < PENDING_EXPIRY);
+                }
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+                _ = rx.changed(), if drain_deadline.is_none() => {
                     break;
                 }
+                _ = shutdown.changed(), if drain_deadline.is_none() => {
+                    info!(
+                        in_flight = tasks.len(),
+                        forwarded = pending.lock().unwrap().len(),
+                        "Shutting down, waiting on in-flight requests"
+                    );
+                    drain_deadline = Some(tokio::time::Instant::now() + request_timeout);
+                }
+                _ = sleep_until_deadline(drain_deadline) => {
+                    warn!(
+                        in_flight = tasks.len(),
+                        "Timed out waiting on in-flight requests"
+                    );
+                }
+            }
+
+            let drained = tasks.is_empty() && pending.lock().unwrap().is_empty();
+            let timed_out =
+                drain_deadline.is_some_and(|deadline| deadline <= tokio::time::Instant::now());
+            if drain_deadline.is_some() && (drained || timed_out) {
+                // payments are recorded before they are forwarded, so the ones still waiting
+                // on the wallet are resumed when we start again
+                let unanswered = pending.lock().unwrap().len();
+                if unanswered > 0 {
+                    warn!(
+                        unanswered,
+                        "Shutting down with forwarded payments unanswered"
+                    );
+                }
+                tasks.shutdown().await;
+                status.set_client(None);
+                client.disconnect().await?;
+                info!("Subscriber stopped");
+                return Ok(());
             }
         }
 
+        status.set_client(None);
         client.disconnect().await?;
     }
 }
 
+/// Replaces a relay that is down with a new connection to it, subscribed to the same filters
+async fn reconnect(client: &Client, url: &Url) -> anyhow::Result<()> {
+    client.remove_relay(url.as_str()).await?;
+    client.add_relay(url.as_str(), None).await?;
+    client.connect_relay(url.as_str()).await?;
+    Ok(())
+}
+
+/// Runs an event handler once a worker is free, counting the events that have to wait for one
+fn enqueue<F>(
+    tasks: &mut JoinSet<()>,
+    workers: &Arc<Semaphore>,
+    metrics: &Metrics,
+    kind: &str,
+    handler: F,
+) where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let permit = workers.clone().try_acquire_owned();
+    if permit.is_err() {
+        metrics.events_delayed.with_label_values(&[kind]).inc();
+    }
+    let workers = workers.clone();
+    tasks.spawn(async move {
+        let _permit = match permit {
+            Ok(permit) => permit,
+            Err(_) => workers
+                .acquire_owned()
+                .await
+                .expect("workers are never closed"),
+        };
+        handler.await
+    });
+}
+
+/// Resolves at the deadline, or never if there is none
+async fn sleep_until_deadline(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Fails payments the wallet hasn't answered within `PENDING_EXPIRY`, they would otherwise
+/// count against the service's budget forever
+pub async fn expire_pending_payments(state: State) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let failed = state
+            .db_pool
+            .get()
+            .map_err(anyhow::Error::from)
+            .and_then(|mut conn| {
+                let cutoff =
+                    chrono::Utc::now().naive_utc() - chrono::Duration::from_std(PENDING_EXPIRY)?;
+                Ok(Payment::fail_stale(&mut conn, cutoff)?)
+            });
+        match failed {
+            Ok(0) => {}
+            Ok(failed) => warn!(failed, "Failed payments the wallet never answered"),
+            Err(e) => error!("Error failing unanswered payments: {e}"),
+        }
+    }
+}
+
+/// Stops watching the keys of service connections once they expire, and lets their users know
+pub async fn purge_expired_keys(state: State) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let expired = state
+            .db_pool
+            .get()
+            .map_err(anyhow::Error::from)
+            .and_then(|mut conn| {
+                let now = chrono::Utc::now().naive_utc();
+                Ok(ServiceNwc::get_expired_keys(&mut conn, now)?)
+            });
+        let expired = match expired {
+            Ok(expired) => expired,
+            Err(e) => {
+                error!("Error loading expired service connections: {e}");
+                continue;
+            }
+        };
+
+        let mut purged = vec![];
+        state.pubkeys.lock().unwrap().send_if_modified(|current| {
+            current.retain(|key| {
+                let expired = expired.contains(key);
+                if expired {
+                    purged.push(*key);
+                }
+                !expired
+            });
+            if !purged.is_empty() {
+                info!(purged = purged.len(), "Purged expired service connections");
+            }
+            !purged.is_empty()
+        });
+
+        for key in purged {
+            if let Err(e) = notify_expired(&state, &key).await {
+                warn!(service_key = %key, "Could not tell the user their connection expired: {e}");
+            }
+        }
+    }
+}
+
+/// Sends the user of an expired service connection a direct message about it
+async fn notify_expired(state: &State, request_key: &XOnlyPublicKey) -> anyhow::Result<()> {
+    let service_nwc = {
+        let conn = &mut state.db_pool.get()?;
+        ServiceNwc::find_by_request_key(conn, request_key)?
+    };
+    let Some(service_nwc) = service_nwc else {
+        return Ok(());
+    };
+
+    let message = format!(
+        "Your wallet connection to {} has expired",
+        service_nwc.service_name()
+    );
+    state
+        .subscriber
+        .direct_message(state, &service_nwc.user_pubkey(), &message)
+        .await
+}
+
+/// Responds to a NIP-42 auth challenge with the proxy's identity
+async fn authenticate(
+    client: &Client,
+    identity: &Keys,
+    url: Url,
+    challenge: String,
+) -> anyhow::Result<()> {
+    let event = EventBuilder::auth(challenge, url.clone()).to_event(identity)?;
+    client
+        .send_msg_to(url.clone(), ClientMessage::new_auth(event))
+        .await?;
+
+    info!("Authenticated to {url}");
+
+    Ok(())
+}
+
+/// A single payment from a service's request
+struct PaymentRequest {
+    /// Set for multi payment requests, identifies the payment in responses
+    d_tag: Option<String>,
+    target: PaymentTarget,
+    payment_hash: Option<String>,
+    amount_msats: Option<u64>,
+    /// Node being paid, known once the invoice is parsed
+    payee: Option<PublicKey>,
+}
+
+enum PaymentTarget {
+    Invoice(String),
+    Keysend(PayKeysendParams),
+}
+
+impl PaymentRequest {
+    fn invoice(&self) -> Option<&str> {
+        match &self.target {
+            PaymentTarget::Invoice(invoice) => Some(invoice),
+            PaymentTarget::Keysend(_) => None,
+        }
+    }
+
+    /// The request that pays this on its own
+    fn forward(&self) -> (NwcMethod, NwcRequest) {
+        match &self.target {
+            PaymentTarget::Invoice(invoice) => {
+                let params = PayInvoiceParams {
+                    invoice: invoice.clone(),
+                    amount: self.amount_msats,
+                };
+                (
+                    NwcMethod::PayInvoice,
+                    NwcRequest::new(NwcMethod::PayInvoice, params),
+                )
+            }
+            PaymentTarget::Keysend(params) => (
+                NwcMethod::PayKeysend,
+                NwcRequest::new(NwcMethod::PayKeysend, params),
+            ),
+        }
+    }
+}
+
+/// Checks a service's request and forwards its payments, `deadline` is when the request times out
 async fn handle_request(
-    db_pool: Pool<ConnectionManager<SqliteConnection>>,
+    state: &State,
     client: &Client,
+    pending: &PendingRequests,
+    wallet_methods: &WalletMethods,
     event: Event,
-) -> anyhow::Result<Option<Event>> {
+    deadline: tokio::time::Instant,
+) -> anyhow::Result<Vec<Event>> {
     debug_assert!(event.kind == Kind::WalletConnectRequest);
+    debug!("Received request");
+    let State {
+        db_pool,
+        master_key,
+        metrics,
+        config,
+        ..
+    } = state;
+    let reject = |reason: &str| {
+        warn!(reason, "Rejected request");
+        metrics.requests_rejected.with_label_values(&[reason]).inc()
+    };
+
     let request_key = {
-        let p_tag = event.tags.into_iter().find_map(|tag| {
+        let p_tag = event.tags.iter().find_map(|tag| {
             if let Tag::PubKey(p, _) = tag {
-                Some(p)
+                Some(*p)
             } else {
                 None
             }
@@ -126,75 +744,737 @@ async fn handle_request(
         if let Some(p_tag) = p_tag {
             p_tag
         } else {
+            reject("no_p_tag");
             return Err(anyhow!("No p tag found"));
         }
     };
 
-    let db = &mut db_pool.get()?;
-    let service_nwc: ServiceNwc = {
-        let opt = ServiceNwc::find_by_request_key(db, &request_key)?;
-        opt.ok_or(anyhow!("No service nwc found"))?
+    Span::current().record("service_key", request_key.to_string());
+
+    // connections are only taken for each query, never while waiting on relays, so the
+    // workers can't starve the rest of the proxy of connections
+    let service_nwc = ServiceNwc::find_by_request_key(&mut *db_pool.get()?, &request_key)?;
+    let service_nwc: ServiceNwc = match service_nwc {
+        Some(service_nwc) => service_nwc,
+        None => {
+            reject("unknown_service");
+            return Err(anyhow!("No service nwc found"));
+        }
     };
 
-    let response_key = service_nwc.response_key();
+    Span::current().record("user_pubkey", service_nwc.user_pubkey().to_string());
+
+    let response_key = service_nwc.response_key(master_key)?;
 
     let context = Secp256k1::new();
     if event.pubkey != response_key.x_only_public_key(&context).0 {
+        reject("pubkey_mismatch");
         return Err(anyhow!("Event pubkey does not match response key"));
     }
 
     let decrypted = decrypt(&response_key, &service_nwc.request_key(), &event.content)?;
-    let req: Request = Request::from_json(decrypted)?;
+    let req = match NwcRequest::from_json(decrypted) {
+        Ok(req) => req,
+        Err(e) => {
+            reject("invalid_request");
+            return Err(e);
+        }
+    };
 
-    // only respond to pay invoice requests
-    if req.method != Method::PayInvoice {
-        return Ok(None);
-    }
+    let respond_error = |method: &str, d_tag: Option<String>, code: ErrorCode, message: &str| {
+        let response = NwcResponse::error(method, code, message);
+        respond(state, client, &service_nwc, event.id, response, d_tag)
+    };
+
+    let method = match NwcMethod::from_str(&req.method) {
+        Ok(method) => method,
+        Err(_) => {
+            reject("unsupported_method");
+            let message = format!("{} is not supported", req.method);
+            respond_error(&req.method, None, ErrorCode::NotImplemented, &message).await?;
+            return Ok(vec![]);
+        }
+    };
+
+    metrics
+        .requests_received
+        .with_label_values(&[method.as_str()])
+        .inc();
 
-    // todo check spending conditions
+    if service_nwc.is_expired() {
+        reject("expired");
+        let message = "This connection has expired";
+        respond_error(method.as_str(), None, ErrorCode::Unauthorized, message).await?;
+        return Err(anyhow!("Service nwc expired"));
+    }
 
-    let user_nwc: UserNwc = {
-        let vec = UserNwc::find_by_user(db, &service_nwc.user_pubkey())?;
-        vec.first().cloned().ok_or(anyhow!("No user nwc found"))?
+    let payments: Vec<PaymentRequest> = match method {
+        NwcMethod::PayInvoice => match req.params::<PayInvoiceParams>() {
+            Ok(params) => vec![PaymentRequest {
+                d_tag: None,
+                target: PaymentTarget::Invoice(params.invoice),
+                payment_hash: None,
+                amount_msats: params.amount,
+                payee: None,
+            }],
+            Err(e) => {
+                reject("invalid_request");
+                respond_error(method.as_str(), None, ErrorCode::Other, &e.to_string()).await?;
+                return Err(e);
+            }
+        },
+        NwcMethod::MultiPayInvoice => match req.params::<MultiPayInvoiceParams>() {
+            Ok(params) if params.invoices.len() > MAX_MULTI_PAY_INVOICES => {
+                reject("too_many_invoices");
+                let message = format!("At most {MAX_MULTI_PAY_INVOICES} invoices per request");
+                respond_error(method.as_str(), None, ErrorCode::Other, &message).await?;
+                return Ok(vec![]);
+            }
+            Ok(params) => params
+                .invoices
+                .into_iter()
+                .map(|item| PaymentRequest {
+                    d_tag: item.id,
+                    target: PaymentTarget::Invoice(item.invoice),
+                    payment_hash: None,
+                    amount_msats: item.amount,
+                    payee: None,
+                })
+                .collect(),
+            Err(e) => {
+                reject("invalid_request");
+                respond_error(method.as_str(), None, ErrorCode::Other, &e.to_string()).await?;
+                return Err(e);
+            }
+        },
+        NwcMethod::PayKeysend => match req.params::<PayKeysendParams>() {
+            Ok(params) => vec![PaymentRequest {
+                d_tag: None,
+                payment_hash: None,
+                amount_msats: Some(params.amount),
+                payee: Some(params.pubkey),
+                target: PaymentTarget::Keysend(params),
+            }],
+            Err(e) => {
+                reject("invalid_request");
+                respond_error(method.as_str(), None, ErrorCode::Other, &e.to_string()).await?;
+                return Err(e);
+            }
+        },
+        NwcMethod::ListTransactions => {
+            // answered from our own records so services only ever see their own payments
+            let response = match req.params::<ListTransactionsParams>() {
+                Ok(params) => list_transactions(&mut *db_pool.get()?, &service_nwc, &params)?,
+                Err(e) => {
+                    reject("invalid_request");
+                    NwcResponse::error(method.as_str(), ErrorCode::Other, e.to_string())
+                }
+            };
+            respond(state, client, &service_nwc, event.id, response, None).await?;
+            return Ok(vec![]);
+        }
+        NwcMethod::MakeInvoice | NwcMethod::LookupInvoice | NwcMethod::GetBalance => {
+            reject("unsupported_method");
+            let message = format!("{method} is not supported");
+            respond_error(method.as_str(), None, ErrorCode::NotImplemented, &message).await?;
+            return Ok(vec![]);
+        }
     };
-    let nwc = user_nwc.nwc_uri();
+    let multi = method == NwcMethod::MultiPayInvoice;
 
-    let invoice = match req.params {
-        RequestParams::PayInvoice(params) => params.invoice,
-        RequestParams::MakeInvoice(_) => return Ok(None),
-        RequestParams::LookupInvoice(_) => return Ok(None),
-        RequestParams::GetBalance => return Ok(None),
+    // read the invoices, anything we can't read is answered straight away
+    let mut valid = Vec::with_capacity(payments.len());
+    for mut payment in payments {
+        let parsed = match &payment.target {
+            PaymentTarget::Invoice(invoice) => match Bolt11Invoice::from_str(invoice) {
+                Ok(invoice) => {
+                    let payment_hash = invoice.payment_hash().to_string();
+                    if multi && payment.d_tag.is_none() {
+                        payment.d_tag = Some(payment_hash.clone());
+                    }
+                    payment.payment_hash = Some(payment_hash);
+                    payment.amount_msats = invoice.amount_milli_satoshis().or(payment.amount_msats);
+                    payment.payee = Some(
+                        invoice
+                            .payee_pub_key()
+                            .copied()
+                            .unwrap_or_else(|| invoice.recover_payee_pub_key()),
+                    );
+                    Ok(())
+                }
+                Err(_) => Err(("invalid_invoice", "Could not parse invoice".to_string())),
+            },
+            PaymentTarget::Keysend(params) => match params.validate() {
+                Ok(()) => {
+                    payment.payment_hash = params.payment_hash();
+                    Ok(())
+                }
+                Err(e) => Err(("invalid_keysend", e.to_string())),
+            },
+        };
+
+        // amounts are stored as signed 64 bit msats
+        let parsed = parsed.and_then(|_| match payment.amount_msats {
+            Some(amount) if amount > MAX_AMOUNT_MSATS => {
+                Err(("invalid_amount", "Amount is too large".to_string()))
+            }
+            _ => Ok(()),
+        });
+        match parsed {
+            Ok(()) => valid.push(payment),
+            Err((reason, message)) => {
+                reject(reason);
+                let d_tag = payment.d_tag.clone();
+                respond_error(method.as_str(), d_tag, ErrorCode::Other, &message).await?;
+            }
+        }
+    }
+    if valid.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let user_nwc = UserNwc::find_by_user(&mut *db_pool.get()?, &service_nwc.user_pubkey())?;
+    let user_nwc: UserNwc = match user_nwc.first() {
+        Some(user_nwc) => user_nwc.clone(),
+        None => {
+            reject("no_user_nwc");
+            for payment in &valid {
+                let d_tag = payment.d_tag.clone();
+                let message = "No wallet connected";
+                respond_error(method.as_str(), d_tag, ErrorCode::Unauthorized, message).await?;
+            }
+            return Err(anyhow!("No user nwc found"));
+        }
     };
+    let nwc = user_nwc.nwc_uri(master_key)?;
 
-    let fwd_event = create_nwc_request(&nwc, invoice);
+    // send the batch as is if the wallet can pay it, otherwise pay each invoice on its own
+    let batch = multi
+        && valid.len() > 1
+        && wallet_methods
+            .supports(client, nwc.public_key, NwcMethod::MultiPayInvoice)
+            .await;
 
-    client
-        .send_event_to(nwc.relay_url.as_str(), fwd_event.clone())
+    let policy = SpendingPolicy::new(service_nwc.limits(), &config.policy);
+
+    // check the whole batch against the budget and record the payments before anything is sent,
+    // in one transaction holding the connection locked so concurrent requests, even to other
+    // proxy instances, can't both spend the same budget
+    let (forwards, denied) = db_pool.get()?.transaction(|db| {
+        ServiceNwc::lock(db, &service_nwc.request_key())?;
+        let since = policy.budget_start(chrono::Utc::now().naive_utc());
+        let mut spent_msats = Payment::spent_msats(db, &service_nwc.request_key(), since)?;
+
+        let mut approved = vec![];
+        let mut denied = vec![];
+        for payment in valid {
+            let allowed = match &payment.payee {
+                Some(payee) => policy.check_payee(payee),
+                None => Ok(()),
+            };
+            match allowed.and_then(|_| policy.check(spent_msats, payment.amount_msats)) {
+                Ok(()) => {
+                    spent_msats =
+                        spent_msats.saturating_add(payment.amount_msats.unwrap_or_default());
+                    approved.push(payment);
+                }
+                Err(violation) => denied.push((payment, violation)),
+            }
+        }
+
+        let mut forwards = vec![];
+        if batch && !approved.is_empty() {
+            let invoices = approved
+                .iter()
+                .map(|payment| MultiPayInvoiceItem {
+                    id: payment.d_tag.clone(),
+                    invoice: payment
+                        .invoice()
+                        .expect("only invoices are batched")
+                        .to_string(),
+                    amount: payment.amount_msats,
+                })
+                .collect();
+            let req = NwcRequest::new(
+                NwcMethod::MultiPayInvoice,
+                MultiPayInvoiceParams { invoices },
+            );
+            forwards.push((
+                NwcMethod::MultiPayInvoice,
+                create_nwc_request(&nwc, &req),
+                approved,
+            ));
+        } else {
+            for payment in approved {
+                let (fwd_method, req) = payment.forward();
+                forwards.push((fwd_method, create_nwc_request(&nwc, &req), vec![payment]));
+            }
+        }
+
+        for (_, fwd_event, payments) in &forwards {
+            for payment in payments {
+                let record = Payment::new(
+                    service_nwc.request_key(),
+                    event.id,
+                    fwd_event.id,
+                    payment.d_tag.clone(),
+                    payment.invoice().map(String::from),
+                    payment.payment_hash.clone(),
+                    payment.amount_msats.unwrap_or_default(),
+                );
+                Payment::insert(db, &record)?;
+            }
+        }
+
+        Ok::<_, diesel::result::Error>((forwards, denied))
+    })?;
+
+    for (payment, violation) in denied {
+        reject(violation.reason());
+        let d_tag = payment.d_tag.clone();
+        respond_error(
+            method.as_str(),
+            d_tag,
+            violation.code(),
+            violation.message(),
+        )
         .await?;
+    }
+
+    // forward everything at once so one slow forward doesn't hold up the ones after it
+    let mut sends = JoinSet::new();
+    let mut unsent = HashMap::new();
+    for (fwd_method, fwd_event, payments) in forwards {
+        pending
+            .lock()
+            .unwrap()
+            .insert(fwd_event.id, (fwd_method, Instant::now()));
+        sends.spawn({
+            let state = state.clone();
+            let client = client.clone();
+            let relays = user_nwc.relays();
+            let fwd_event = fwd_event.clone();
+            async move {
+                let result = publish(&state, &client, &relays, &fwd_event).await;
+                (fwd_event.id, result)
+            }
+        });
+        unsent.insert(fwd_event.id, (fwd_method, fwd_event, payments));
+    }
+
+    // stop forwarding early enough to still answer for the payments that didn't go out
+    let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+    let forward_deadline = deadline - PUBLISH_TIMEOUT.min(remaining / 2);
+    let mut forwarded = vec![];
+    let mut failed = vec![];
+    loop {
+        let (id, result) = match tokio::time::timeout_at(forward_deadline, sends.join_next()).await
+        {
+            Ok(Some(sent)) => sent?,
+            Ok(None) => break,
+            Err(_) => {
+                warn!(unsent = unsent.len(), "Timed out forwarding payments");
+                break;
+            }
+        };
+        let (fwd_method, fwd_event, payments) = unsent.remove(&id).expect("forward was sent");
+
+        let relay = match result {
+            Ok(relay) => relay,
+            Err(PublishError::Refused(e)) => {
+                error!("Error forwarding request: {e}");
+                failed.push((fwd_event, payments, "send_failed", "Could not reach wallet"));
+                continue;
+            }
+            Err(PublishError::Unconfirmed(e)) => {
+                // the wallet may have the request anyway, so the payment stays pending and
+                // counts against the budget until the wallet answers
+                warn!(
+                    forwarded_id = %fwd_event.id,
+                    "Forwarded request was not confirmed, waiting on the wallet: {e}"
+                );
+                forwarded.push(fwd_event);
+                continue;
+            }
+        };
 
-    println!("Sent event to {}", nwc.relay_url);
+        metrics
+            .requests_forwarded
+            .with_label_values(&[fwd_method.as_str()])
+            .inc();
 
-    Ok(Some(fwd_event))
+        info!(
+            forwarded_id = %fwd_event.id,
+            %relay,
+            payments = payments.len(),
+            "Forwarded request"
+        );
+        forwarded.push(fwd_event);
+    }
+    // dropping the set stops the forwards still being published
+    drop(sends);
+    failed.extend(unsent.into_values().map(|(_, fwd_event, payments)| {
+        (
+            fwd_event,
+            payments,
+            "send_timeout",
+            "Timed out reaching wallet",
+        )
+    }));
+
+    for (fwd_event, payments, reason, message) in failed {
+        pending.lock().unwrap().remove(&fwd_event.id);
+        reject(reason);
+        {
+            let db = &mut db_pool.get()?;
+            for record in Payment::find_by_forward_id(db, &fwd_event.id)? {
+                Payment::fail(db, record.id())?;
+            }
+        }
+        for payment in &payments {
+            let d_tag = payment.d_tag.clone();
+            respond_error(method.as_str(), d_tag, ErrorCode::Internal, message).await?;
+        }
+    }
+
+    Ok(forwarded)
 }
 
 async fn handle_response(
-    _db_pool: Pool<ConnectionManager<SqliteConnection>>,
-    _client: &Client,
+    state: &State,
+    client: &Client,
+    pending: &PendingRequests,
     event: Event,
 ) -> anyhow::Result<Option<Event>> {
     debug_assert!(event.kind == Kind::WalletConnectResponse);
+    debug!("Received response");
+    let State {
+        db_pool,
+        master_key,
+        metrics,
+        ..
+    } = state;
+
+    let request_id = event.tags.iter().find_map(|tag| {
+        if let Tag::Event(id, _, _) = tag {
+            Some(*id)
+        } else {
+            None
+        }
+    });
+
+    let Some(request_id) = request_id else {
+        debug!("Response without an e tag");
+        return Ok(None);
+    };
+    Span::current().record("request_id", request_id.to_string());
+
+    if state.subscriber.resolve_wallet_request(&request_id, &event) {
+        return Ok(None);
+    }
+
+    let mut conn = db_pool.get()?;
+    let db = &mut conn;
+    let payments = Payment::find_by_forward_id(db, &request_id)?;
+    let Some(first) = payments.first() else {
+        // our own responses to services and responses to requests we did not forward
+        debug!("Not a response to a forwarded payment");
+        return Ok(None);
+    };
+
+    let Some(service_nwc) = ServiceNwc::find_by_request_key(db, &first.service_key())? else {
+        warn!("Service nwc no longer exists");
+        // there is nobody left to answer
+        pending.lock().unwrap().remove(&request_id);
+        return Ok(None);
+    };
+
+    // only the wallet we forwarded to may answer
+    let user_nwc = UserNwc::find_by_user(db, &service_nwc.user_pubkey())?
+        .into_iter()
+        .find(|user_nwc| user_nwc.request_key() == event.pubkey);
+    let Some(user_nwc) = user_nwc else {
+        warn!("Response is not from the user's wallet");
+        return Ok(None);
+    };
+    let nwc = user_nwc.nwc_uri(master_key)?;
+
+    let decrypted = decrypt(&nwc.secret, &event.pubkey, &event.content)?;
+    let response = NwcResponse::from_json(decrypted)?;
+
+    let d_tag = event.tags.iter().find_map(|tag| {
+        if let Tag::Identifier(d) = tag {
+            Some(d.as_str())
+        } else {
+            None
+        }
+    });
+    let payment = match d_tag {
+        Some(d_tag) => payments.iter().find(|p| p.d_tag() == Some(d_tag)),
+        None if payments.len() == 1 => payments.first(),
+        None => None,
+    };
+    let Some(payment) = payment else {
+        warn!(d_tag, "Response does not match a forwarded payment");
+        return Ok(None);
+    };
+    if payment.status() != PaymentStatus::Pending {
+        debug!("Payment was already answered");
+        return Ok(None);
+    }
+
+    match (&response.error, &response.result) {
+        (None, Some(result)) => {
+            let preimage = result["preimage"].as_str().map(|p| p.to_string());
+            let fees_paid = result["fees_paid"].as_u64();
+            Payment::settle(db, payment.id(), preimage, fees_paid)?;
+        }
+        _ => Payment::fail(db, payment.id())?,
+    }
+
+    // a batch gets a response per payment, it is answered once none of them are left waiting.
+    // Loaded again after our own update so concurrent responses to the batch can't both miss it
+    let answered = Payment::find_by_forward_id(db, &request_id)?
+        .iter()
+        .all(|p| p.status() != PaymentStatus::Pending);
+    if answered {
+        if let Some((method, sent_at)) = pending.lock().unwrap().remove(&request_id) {
+            info!(
+                method = method.as_str(),
+                latency_ms = sent_at.elapsed().as_millis() as u64,
+                "Received wallet response"
+            );
+            metrics
+                .upstream_latency
+                .with_label_values(&[method.as_str()])
+                .observe(sent_at.elapsed().as_secs_f64());
+        }
+    }
+
+    // don't hold on to the connection while publishing the response
+    drop(conn);
+
+    // answer with the method the service asked for, multi payments are the ones with a d tag
+    // and keysend payments the ones without an invoice
+    let result_type = match (payment.d_tag(), payment.invoice()) {
+        (Some(_), _) => NwcMethod::MultiPayInvoice,
+        (None, Some(_)) => NwcMethod::PayInvoice,
+        (None, None) => NwcMethod::PayKeysend,
+    };
+    let response = NwcResponse {
+        result_type: result_type.to_string(),
+        ..response
+    };
+    let sent = respond(
+        state,
+        client,
+        &service_nwc,
+        payment.request_id(),
+        response,
+        payment.d_tag().map(String::from),
+    )
+    .await?;
+
+    Ok(sent)
+}
+
+fn list_transactions(
+    db: &mut DbConnection,
+    service_nwc: &ServiceNwc,
+    params: &ListTransactionsParams,
+) -> anyhow::Result<NwcResponse> {
+    let timestamp = |secs: Option<u64>| {
+        secs.and_then(|secs| NaiveDateTime::from_timestamp_opt(secs.try_into().ok()?, 0))
+    };
+    let filter = PaymentFilter {
+        from: timestamp(params.from),
+        until: timestamp(params.until),
+        limit: Some(
+            params
+                .limit
+                .unwrap_or(MAX_TRANSACTIONS)
+                .min(MAX_TRANSACTIONS) as i64,
+        ),
+        offset: params
+            .offset
+            .map(|offset| offset.min(i64::MAX as u64) as i64),
+        settled_only: !params.unpaid,
+    };
 
-    // todo
+    // the proxy only makes payments for services, it never creates invoices for them
+    let transactions = match params.transaction_type {
+        Some(TransactionType::Incoming) => vec![],
+        Some(TransactionType::Outgoing) | None => {
+            Payment::list(db, &service_nwc.request_key(), &filter)?
+                .iter()
+                .map(Transaction::outgoing)
+                .collect()
+        }
+    };
 
-    Ok(None)
+    Ok(NwcResponse::success(
+        NwcMethod::ListTransactions,
+        ListTransactionsResult { transactions },
+    ))
 }
 
-fn create_nwc_request(nwc: &NostrWalletConnectURI, invoice: String) -> Event {
-    let req = Request {
-        method: Method::PayInvoice,
-        params: RequestParams::PayInvoice(PayInvoiceRequestParams { invoice }),
+/// Sends a response to the service, signed as the wallet side of its connection
+async fn respond(
+    state: &State,
+    client: &Client,
+    service_nwc: &ServiceNwc,
+    request_id: EventId,
+    response: NwcResponse,
+    d_tag: Option<String>,
+) -> anyhow::Result<Option<Event>> {
+    let master_key = &state.master_key;
+    let Some(wallet_keys) = service_nwc.wallet_keys(master_key)? else {
+        warn!("Service nwc has no wallet key, can't send response");
+        return Ok(None);
     };
 
+    let service_pubkey = service_nwc
+        .response_key(master_key)?
+        .x_only_public_key(&Secp256k1::new())
+        .0;
+    let encrypted = encrypt(
+        &wallet_keys.secret_key()?,
+        &service_pubkey,
+        response.as_json(),
+    )?;
+    let mut tags = vec![
+        Tag::PubKey(service_pubkey, None),
+        Tag::Event(request_id, None, None),
+    ];
+    if let Some(d_tag) = d_tag {
+        tags.push(Tag::Identifier(d_tag));
+    }
+    let event =
+        EventBuilder::new(Kind::WalletConnectResponse, encrypted, &tags).to_event(&wallet_keys)?;
+
+    let relay = Url::parse(service_nwc.relay_url())?;
+    publish(state, client, &[relay], &event).await?;
+
+    Ok(Some(event))
+}
+
+/// Why an event could not be published
+#[derive(Debug)]
+enum PublishError {
+    /// Every relay answered that it won't take the event, so nobody will see it
+    Refused(anyhow::Error),
+    /// No relay confirmed the event in time, it may still have been delivered
+    Unconfirmed(anyhow::Error),
+}
+
+impl std::fmt::Display for PublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PublishError::Refused(e) => write!(f, "Event refused: {e}"),
+            PublishError::Unconfirmed(e) => write!(f, "Could not publish event: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+/// Publishes an event to the first of `relays` to confirm it with an OK message. Each round
+/// tries the relays in order, so fallbacks are used as soon as the main relay fails, and
+/// rounds are retried with exponential backoff. Returns the relay that accepted the event.
+async fn publish(
+    state: &State,
+    client: &Client,
+    relays: &[Url],
+    event: &Event,
+) -> Result<Url, PublishError> {
+    let health = &state.subscriber.relay_health;
+    let deadline = Instant::now() + PUBLISH_TIMEOUT;
+    let mut relays: Vec<&Url> = relays.iter().collect();
+    let mut backoff = PUBLISH_BACKOFF;
+    let mut last_error = anyhow!("No relays to publish to");
+
+    for attempt in 1..=PUBLISH_ATTEMPTS {
+        let mut refused = vec![];
+        for (i, relay) in relays.iter().enumerate() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let opts = RelaySendOptions::new()
+                .wait_for_ok(true)
+                .timeout(Some(remaining.min(PUBLISH_OK_TIMEOUT)));
+            let sent_at = Instant::now();
+            match send_to(client, relay, event, opts).await {
+                Ok(()) => {
+                    health.record_latency(relay, sent_at.elapsed());
+                    if attempt > 1 || i > 0 {
+                        info!(event_id = %event.id, %relay, attempt, "Published event after retrying");
+                    }
+                    return Ok((*relay).clone());
+                }
+                Err(e) => {
+                    health.record_error(relay, &e);
+                    state
+                        .metrics
+                        .publish_failures
+                        .with_label_values(&[relay.as_str()])
+                        .inc();
+                    warn!(event_id = %event.id, %relay, attempt, "Could not publish event: {e}");
+                    if is_refusal(&e) {
+                        refused.push(*relay);
+                    }
+                    last_error = e;
+                }
+            }
+        }
+
+        // relays that refused the event will refuse it again
+        relays.retain(|relay| !refused.contains(relay));
+        if relays.is_empty() {
+            return Err(PublishError::Refused(last_error));
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if attempt == PUBLISH_ATTEMPTS || remaining <= backoff {
+            break;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+
+    Err(PublishError::Unconfirmed(last_error))
+}
+
+/// Sends an event to one relay and waits for it to confirm, adding the relay if it's new
+async fn send_to(
+    client: &Client,
+    relay: &Url,
+    event: &Event,
+    opts: RelaySendOptions,
+) -> anyhow::Result<()> {
+    if !client.relays().await.contains_key(relay) {
+        client.add_relay(relay.as_str(), None).await?;
+        client.connect_relay(relay.as_str()).await?;
+    }
+    client
+        .pool()
+        .send_event_to(relay.clone(), event.clone(), opts)
+        .await?;
+    Ok(())
+}
+
+/// If a relay answered that it won't take the event, as opposed to a transient failure
+fn is_refusal(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<pool::Error>() {
+        Some(pool::Error::Relay(relay::Error::EventNotPublished(message))) => {
+            ["blocked:", "invalid:", "pow:", "restricted:"]
+                .iter()
+                .any(|prefix| message.starts_with(prefix))
+        }
+        _ => false,
+    }
+}
+
+fn create_nwc_request(nwc: &NostrWalletConnectURI, req: &NwcRequest) -> Event {
     let encrypted = encrypt(&nwc.secret, &nwc.public_key, req.as_json()).unwrap();
     let p_tag = Tag::PubKey(nwc.public_key, None);
 
@@ -202,3 +1482,526 @@ fn create_nwc_request(nwc: &NostrWalletConnectURI, invoice: String) -> Event {
         .to_event(&Keys::new(nwc.secret))
         .unwrap()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encryption::MasterKey;
+    use crate::mock::relay::MockRelay;
+    use crate::mock::test_state;
+    use crate::mock::wallet::{create_invoice, pay_everything, MockWallet, Script, WalletReply};
+    use crate::models::service_nwc::SpendingLimits;
+    use crate::models::test::{create_database, gen_tmp_db_name, teardown_database};
+    use crate::models::user::User;
+    use bitcoin::secp256k1::rand;
+    use bitcoin::secp256k1::SecretKey;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::watch;
+    use tokio::task::JoinHandle;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// A proxy with one user, their mock wallet and a service connection, all on a mock relay
+    struct Harness {
+        relay: MockRelay,
+        wallet: MockWallet,
+        state: State,
+        service: NostrWalletConnectURI,
+        subscriber: JoinHandle<anyhow::Result<()>>,
+        shutdown: watch::Sender<bool>,
+        db_name: String,
+    }
+
+    impl Harness {
+        async fn start(wallet_methods: &[NwcMethod], limits: SpendingLimits) -> Self {
+            Self::start_with_latency(wallet_methods, limits, Duration::ZERO).await
+        }
+
+        /// Starts with a wallet that waits `latency` before answering each request
+        async fn start_with_latency(
+            wallet_methods: &[NwcMethod],
+            limits: SpendingLimits,
+            latency: Duration,
+        ) -> Self {
+            Self::start_with_script(wallet_methods, limits, pay_everything(), latency).await
+        }
+
+        /// Starts with a wallet that answers requests as `script` says
+        async fn start_with_script(
+            wallet_methods: &[NwcMethod],
+            limits: SpendingLimits,
+            script: Script,
+            latency: Duration,
+        ) -> Self {
+            let relay = MockRelay::start().await.unwrap();
+            let wallet = MockWallet::start(relay.url(), wallet_methods, script, latency)
+                .await
+                .unwrap();
+
+            let db_name = gen_tmp_db_name();
+            let master_key = MasterKey::generate().0;
+            let service = {
+                let conn = &mut create_database(&db_name);
+                let user_pubkey =
+                    SecretKey::new(&mut rand::thread_rng()).public_key(&Secp256k1::new());
+                User::create(conn, user_pubkey).unwrap();
+                UserNwc::create(conn, wallet.nwc_uri(), user_pubkey, &master_key).unwrap();
+                let service = ServiceNwc::generate(
+                    user_pubkey,
+                    "service".to_string(),
+                    relay.url(),
+                    &master_key,
+                )
+                .with_limits(&limits);
+                ServiceNwc::insert(conn, &service).unwrap();
+                service.nwc_uri(&master_key, None).unwrap()
+            };
+
+            let (state, rx) = test_state(&db_name, relay.url(), master_key).unwrap();
+            let (shutdown, shutdown_rx) = watch::channel(false);
+            let subscriber = tokio::spawn(start_subscription(state.clone(), rx, shutdown_rx));
+            relay
+                .wait_for_subscriber(service.public_key, TIMEOUT)
+                .await
+                .unwrap();
+
+            Self {
+                relay,
+                wallet,
+                state,
+                service,
+                subscriber,
+                shutdown,
+                db_name,
+            }
+        }
+
+        /// Sends a request as the service and returns its event
+        fn send(&self, req: NwcRequest) -> Event {
+            let event = create_nwc_request(&self.service, &req);
+            self.relay.publish(event.clone());
+            event
+        }
+
+        /// Waits for the proxy's response to a service request, with the given d tag
+        async fn response(&self, request: &Event, d_tag: Option<&str>) -> NwcResponse {
+            let mut filter = Filter::new()
+                .kind(Kind::WalletConnectResponse)
+                .event(request.id)
+                .author(self.service.public_key.to_string());
+            if let Some(d_tag) = d_tag {
+                filter = filter.identifier(d_tag);
+            }
+            let event = self.relay.wait_for(filter, TIMEOUT).await.unwrap();
+            let decrypted = decrypt(&self.service.secret, &event.pubkey, &event.content).unwrap();
+            NwcResponse::from_json(decrypted).unwrap()
+        }
+
+        /// Requests the proxy forwarded to the user's wallet
+        fn forwarded(&self) -> Vec<Event> {
+            let wallet = self.wallet.nwc_uri();
+            self.relay
+                .events()
+                .into_iter()
+                .filter(|event| {
+                    event.kind == Kind::WalletConnectRequest
+                        && event.tags.contains(&Tag::PubKey(wallet.public_key, None))
+                })
+                .collect()
+        }
+
+        async fn stop(self) {
+            self.subscriber.abort();
+            self.wallet.stop().await.unwrap();
+            teardown_database(&self.db_name);
+        }
+    }
+
+    #[test]
+    fn test_create_nwc_request() {
+        let nwc = NostrWalletConnectURI {
+            public_key: Keys::generate().public_key(),
+            secret: Keys::generate().secret_key().unwrap(),
+            relay_url: "wss://relay.example.com".parse().unwrap(),
+            lud16: None,
+        };
+        let req = NwcRequest::new(NwcMethod::GetBalance, serde_json::Value::Null);
+
+        let event = create_nwc_request(&nwc, &req);
+        event.verify().unwrap();
+        assert_eq!(event.kind, Kind::WalletConnectRequest);
+        assert_eq!(event.pubkey, Keys::new(nwc.secret).public_key());
+        assert_eq!(event.tags, vec![Tag::PubKey(nwc.public_key, None)]);
+
+        let decrypted = decrypt(&nwc.secret, &nwc.public_key, &event.content).unwrap();
+        assert_eq!(
+            NwcRequest::from_json(decrypted).unwrap().method,
+            "get_balance"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pay_invoice_end_to_end() {
+        let harness = Harness::start(&[NwcMethod::PayInvoice], SpendingLimits::default()).await;
+
+        let invoice = create_invoice(10_000).unwrap().to_string();
+        let params = PayInvoiceParams {
+            invoice: invoice.clone(),
+            amount: None,
+        };
+        let request = harness.send(NwcRequest::new(NwcMethod::PayInvoice, params));
+
+        let response = harness.response(&request, None).await;
+        assert_eq!(response.result_type, "pay_invoice");
+        assert!(response.error.is_none());
+        assert_eq!(response.result.unwrap()["preimage"], "00".repeat(32));
+
+        // the wallet was asked to pay the same invoice, by the user's connection
+        let forwarded = harness.forwarded();
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(
+            forwarded[0].pubkey,
+            Keys::new(harness.wallet.nwc_uri().secret).public_key()
+        );
+        let requests = harness.wallet.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "pay_invoice");
+        assert_eq!(requests[0].params["invoice"], invoice);
+
+        let db = &mut harness.state.db_pool.get().unwrap();
+        let payments = Payment::find_by_forward_id(db, &forwarded[0].id).unwrap();
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].status(), PaymentStatus::Settled);
+        assert_eq!(payments[0].request_id(), request.id);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_over_payment_cap_end_to_end() {
+        let limits = SpendingLimits {
+            max_payment_sats: Some(5),
+            ..Default::default()
+        };
+        let harness = Harness::start(&[NwcMethod::PayInvoice], limits).await;
+
+        let params = PayInvoiceParams {
+            invoice: create_invoice(10_000).unwrap().to_string(),
+            amount: None,
+        };
+        let request = harness.send(NwcRequest::new(NwcMethod::PayInvoice, params));
+
+        let response = harness.response(&request, None).await;
+        let error = response.error.unwrap();
+        assert!(matches!(error.code, ErrorCode::Restricted));
+
+        // nothing reaches the wallet
+        assert!(harness.forwarded().is_empty());
+        assert!(harness.wallet.requests().is_empty());
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_keysend_amount_too_large_end_to_end() {
+        let harness = Harness::start(&[NwcMethod::PayKeysend], SpendingLimits::default()).await;
+
+        let params = PayKeysendParams {
+            amount: u64::MAX,
+            pubkey: PublicKey::from_slice(&[2; 33]).unwrap(),
+            preimage: None,
+            tlv_records: vec![],
+        };
+        let request = harness.send(NwcRequest::new(NwcMethod::PayKeysend, params));
+
+        let response = harness.response(&request, None).await;
+        assert_eq!(response.error.unwrap().message, "Amount is too large");
+        assert!(harness.forwarded().is_empty());
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_multi_pay_invoice_end_to_end() {
+        let methods = [NwcMethod::PayInvoice, NwcMethod::MultiPayInvoice];
+        let harness = Harness::start(&methods, SpendingLimits::default()).await;
+
+        let invoices = ["a", "b"]
+            .into_iter()
+            .map(|id| MultiPayInvoiceItem {
+                id: Some(id.to_string()),
+                invoice: create_invoice(1_000).unwrap().to_string(),
+                amount: None,
+            })
+            .collect();
+        let req = NwcRequest::new(
+            NwcMethod::MultiPayInvoice,
+            MultiPayInvoiceParams { invoices },
+        );
+        let request = harness.send(req);
+
+        for id in ["a", "b"] {
+            let response = harness.response(&request, Some(id)).await;
+            assert_eq!(response.result_type, "multi_pay_invoice");
+            assert!(response.error.is_none());
+        }
+
+        // the wallet can pay batches so it gets the request as one
+        let requests = harness.wallet.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "multi_pay_invoice");
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_multi_pay_invoice_too_many_invoices() {
+        let methods = [NwcMethod::PayInvoice, NwcMethod::MultiPayInvoice];
+        let harness = Harness::start(&methods, SpendingLimits::default()).await;
+
+        let invoices = (0..=MAX_MULTI_PAY_INVOICES)
+            .map(|_| MultiPayInvoiceItem {
+                id: None,
+                invoice: create_invoice(1_000).unwrap().to_string(),
+                amount: None,
+            })
+            .collect();
+        let req = NwcRequest::new(
+            NwcMethod::MultiPayInvoice,
+            MultiPayInvoiceParams { invoices },
+        );
+        let request = harness.send(req);
+
+        // the whole request is refused with a single response and nothing is recorded
+        let response = harness.response(&request, None).await;
+        assert_eq!(
+            response.error.unwrap().message,
+            format!("At most {MAX_MULTI_PAY_INVOICES} invoices per request")
+        );
+        assert!(harness.forwarded().is_empty());
+        let db = &mut harness.state.db_pool.get().unwrap();
+        let service_key = harness.service.public_key;
+        assert!(Payment::list(db, &service_key, &PaymentFilter::default())
+            .unwrap()
+            .is_empty());
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_forwarded_payments() {
+        let latency = Duration::from_millis(500);
+        let mut harness = Harness::start_with_latency(
+            &[NwcMethod::PayInvoice],
+            SpendingLimits::default(),
+            latency,
+        )
+        .await;
+
+        let params = PayInvoiceParams {
+            invoice: create_invoice(10_000).unwrap().to_string(),
+            amount: None,
+        };
+        let request = harness.send(NwcRequest::new(NwcMethod::PayInvoice, params));
+        let wallet = harness.wallet.nwc_uri().public_key;
+        let forwarded = Filter::new()
+            .kind(Kind::WalletConnectRequest)
+            .pubkey(wallet);
+        harness.relay.wait_for(forwarded, TIMEOUT).await.unwrap();
+
+        // shut down while the wallet is still paying
+        harness.shutdown.send(true).unwrap();
+
+        // new requests are no longer taken
+        let params = PayInvoiceParams {
+            invoice: create_invoice(10_000).unwrap().to_string(),
+            amount: None,
+        };
+        let late = harness.send(NwcRequest::new(NwcMethod::PayInvoice, params));
+
+        // but the payment already forwarded is still answered before the subscriber stops
+        let response = harness.response(&request, None).await;
+        assert!(response.error.is_none());
+        let stopped = tokio::time::timeout(TIMEOUT, &mut harness.subscriber).await;
+        assert!(stopped.unwrap().unwrap().is_ok());
+
+        assert_eq!(harness.wallet.requests().len(), 1);
+        assert!(!harness
+            .relay
+            .events()
+            .iter()
+            .any(|event| event.tags.contains(&Tag::Event(late.id, None, None))));
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_whole_batch() {
+        // the wallet pays the second invoice of a batch a while after the first
+        let script: Script = Arc::new(|req: &NwcRequest| {
+            let params = req.params::<MultiPayInvoiceParams>().unwrap();
+            params
+                .invoices
+                .into_iter()
+                .enumerate()
+                .map(|(i, item)| WalletReply {
+                    response: NwcResponse::success(
+                        NwcMethod::MultiPayInvoice,
+                        serde_json::json!({ "preimage": "00".repeat(32) }),
+                    ),
+                    d_tag: item.id,
+                    delay: Duration::from_millis(500 * i as u64),
+                })
+                .collect()
+        });
+        let methods = [NwcMethod::PayInvoice, NwcMethod::MultiPayInvoice];
+        let mut harness =
+            Harness::start_with_script(&methods, SpendingLimits::default(), script, Duration::ZERO)
+                .await;
+
+        let invoices = ["a", "b"]
+            .into_iter()
+            .map(|id| MultiPayInvoiceItem {
+                id: Some(id.to_string()),
+                invoice: create_invoice(1_000).unwrap().to_string(),
+                amount: None,
+            })
+            .collect();
+        let req = NwcRequest::new(
+            NwcMethod::MultiPayInvoice,
+            MultiPayInvoiceParams { invoices },
+        );
+        let request = harness.send(req);
+
+        // shut down once the first invoice is answered
+        let response = harness.response(&request, Some("a")).await;
+        assert!(response.error.is_none());
+        harness.shutdown.send(true).unwrap();
+
+        // the rest of the batch is still answered before the subscriber stops
+        let response = harness.response(&request, Some("b")).await;
+        assert!(response.error.is_none());
+        let stopped = tokio::time::timeout(TIMEOUT, &mut harness.subscriber).await;
+        assert!(stopped.unwrap().unwrap().is_ok());
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_notify_expired() {
+        let harness = Harness::start(&[NwcMethod::PayInvoice], SpendingLimits::default()).await;
+
+        let service_key = harness.service.public_key;
+        notify_expired(&harness.state, &service_key).await.unwrap();
+
+        // the user gets a direct message from the proxy's identity
+        let user_pubkey = {
+            let db = &mut harness.state.db_pool.get().unwrap();
+            ServiceNwc::find_by_request_key(db, &service_key)
+                .unwrap()
+                .unwrap()
+                .user_pubkey()
+        };
+        let receiver = user_pubkey.x_only_public_key().0;
+        let filter = Filter::new()
+            .kind(Kind::EncryptedDirectMessage)
+            .pubkey(receiver);
+        let event = harness.relay.wait_for(filter, TIMEOUT).await.unwrap();
+        assert_eq!(event.pubkey, harness.state.identity.public_key());
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_publish_falls_back() {
+        let relay = MockRelay::start().await.unwrap();
+        let db_name = gen_tmp_db_name();
+        create_database(&db_name);
+        let (state, _) = test_state(&db_name, relay.url(), MasterKey::generate().0).unwrap();
+        let keys = Keys::generate();
+        let client = Client::new(&keys);
+
+        // nothing listens on the primary, so its attempt times out
+        let primary = Url::parse("ws://127.0.0.1:1").unwrap();
+        let fallback = Url::parse(relay.url()).unwrap();
+        let event = EventBuilder::new_text_note("hello", &[])
+            .to_event(&keys)
+            .unwrap();
+        let published = publish(
+            &state,
+            &client,
+            &[primary.clone(), fallback.clone()],
+            &event,
+        )
+        .await
+        .unwrap();
+        assert_eq!(published, fallback);
+        assert!(relay.events().iter().any(|e| e.id == event.id));
+        let failures = |relay: &Url| {
+            state
+                .metrics
+                .publish_failures
+                .with_label_values(&[relay.as_str()])
+                .get()
+        };
+        assert_eq!(failures(&primary), 1);
+        assert_eq!(failures(&fallback), 0);
+
+        // relays that refuse an event aren't asked again
+        let mut invalid = EventBuilder::new_text_note("hello", &[])
+            .to_event(&keys)
+            .unwrap();
+        invalid.content = "tampered".to_string();
+        assert!(matches!(
+            publish(&state, &client, std::slice::from_ref(&fallback), &invalid).await,
+            Err(PublishError::Refused(_))
+        ));
+        assert_eq!(failures(&fallback), 1);
+
+        // a relay that never confirms may still have the event
+        assert!(matches!(
+            publish(&state, &client, std::slice::from_ref(&primary), &event).await,
+            Err(PublishError::Unconfirmed(_))
+        ));
+
+        let report = state.subscriber.relay_report();
+        let fallback_report = report
+            .iter()
+            .find(|r| r.url == fallback.to_string())
+            .unwrap();
+        assert!(fallback_report.latency_ms.is_some());
+        assert_eq!(fallback_report.errors, 1);
+        assert!(fallback_report
+            .last_error
+            .as_deref()
+            .is_some_and(|e| e.contains("invalid:")));
+
+        client.disconnect().await.unwrap();
+        teardown_database(&db_name);
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_limits_workers() {
+        let metrics = Metrics::new().unwrap();
+        let workers = Arc::new(Semaphore::new(2));
+        let mut tasks = JoinSet::new();
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..5 {
+            let running = running.clone();
+            let most_running = most_running.clone();
+            enqueue(&mut tasks, &workers, &metrics, "request", async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most_running.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+
+        assert_eq!(most_running.load(Ordering::SeqCst), 2);
+        let delayed = metrics.events_delayed.with_label_values(&["request"]).get();
+        assert_eq!(delayed, 3);
+    }
+}
@@ -0,0 +1,56 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::{error, info};
+
+/// How often the certificate and key files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Certificate and private key files for serving the API over TLS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    /// Loads the PEM encoded certificate chain and private key
+    pub async fn load(&self) -> anyhow::Result<RustlsConfig> {
+        RustlsConfig::from_pem_file(&self.cert, &self.key)
+            .await
+            .map_err(|e| anyhow::anyhow!("Could not load TLS certificate: {e}"))
+    }
+
+    /// Reloads the certificate whenever either file changes, so renewed certificates
+    /// are picked up without a restart. A failed reload keeps serving the old certificate.
+    pub async fn watch(self, rustls: RustlsConfig) {
+        let mut last_modified = self.modified();
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let modified = self.modified();
+            if modified == last_modified {
+                continue;
+            }
+
+            match rustls.reload_from_pem_file(&self.cert, &self.key).await {
+                Ok(()) => {
+                    info!(cert = %self.cert.display(), "Reloaded TLS certificate");
+                    last_modified = modified;
+                }
+                // the files may be mid-write, try again next tick
+                Err(e) => error!("Could not reload TLS certificate: {e}"),
+            }
+        }
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        (modified(&self.cert), modified(&self.key))
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
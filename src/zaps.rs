@@ -0,0 +1,466 @@
+//! NIP-57 zaps to users' lightning addresses. Zap requests sent to the LNURL callback are
+//! stored with their invoice, and once the user's wallet is paid a receipt signed by the
+//! proxy's identity is published to the relays the sender listed.
+
+use crate::models::user_nwc::UserNwc;
+use crate::models::zap::Zap;
+use crate::nwc::{NwcMethod, NwcRequest};
+use crate::State;
+use anyhow::anyhow;
+use nostr::prelude::decrypt;
+use nostr::url::Host;
+use nostr::{Event, EventBuilder, Keys, Kind, Tag, TagKind, Url};
+use nostr_sdk::{Client, Options, RelaySendOptions};
+use serde::Deserialize;
+use serde_json::Value;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+
+/// NIP-47 wallet notifications, not yet known to the nostr crate
+pub const NOTIFICATION_KIND: u64 = 23196;
+
+/// How often pending zaps are looked up in the user's wallet
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+/// How long zaps are still looked up after their invoice expires, so one paid just before it
+/// expired still gets a receipt
+const EXPIRY_GRACE: Duration = Duration::from_secs(30);
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most relays a receipt is published to, senders pick them so don't trust the list's size
+const MAX_RECEIPT_RELAYS: usize = 10;
+/// Most relays the receipt client stays connected to between receipts
+const MAX_RECEIPT_CLIENT_RELAYS: usize = 50;
+/// Most unpaid zaps a user can have, the LNURL callback is open to anyone
+pub const MAX_PENDING_ZAPS: i64 = 20;
+/// Pending zaps looked up in users' wallets at once
+const LOOKUP_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Clone, Deserialize)]
+struct Notification {
+    notification_type: String,
+    notification: Value,
+}
+
+/// Checks a zap request is well formed and for the amount being paid, per NIP-57 appendix D
+pub fn validate_zap_request(request: &str, amount_msats: u64) -> anyhow::Result<Event> {
+    let event = Event::from_json(request).map_err(|_| anyhow!("Invalid zap request"))?;
+    if event.kind != Kind::ZapRequest {
+        return Err(anyhow!("Zap request must be kind 9734"));
+    }
+    event
+        .verify()
+        .map_err(|_| anyhow!("Invalid zap request signature"))?;
+
+    let count = |kind: TagKind| event.tags.iter().filter(|t| t.kind() == kind).count();
+    if count(TagKind::P) != 1 {
+        return Err(anyhow!("Zap request must have exactly one p tag"));
+    }
+    if count(TagKind::E) > 1 {
+        return Err(anyhow!("Zap request must have at most one e tag"));
+    }
+    if receipt_relays(&event).is_empty() {
+        return Err(anyhow!("Zap request must list a public wss relay"));
+    }
+    let amount = event.tags.iter().find_map(|t| match t {
+        Tag::Amount(amount) => Some(*amount),
+        _ => None,
+    });
+    if amount.is_some_and(|amount| amount != amount_msats) {
+        return Err(anyhow!("Zap request amount does not match"));
+    }
+
+    Ok(event)
+}
+
+/// The kind 9735 receipt for a paid zap, the description is the request exactly as the
+/// sender gave it so its hash matches the invoice
+fn build_receipt(identity: &Keys, zap: &Zap, preimage: Option<String>) -> anyhow::Result<Event> {
+    let request = Event::from_json(zap.request())?;
+
+    let mut tags = vec![
+        Tag::Bolt11(zap.invoice().to_string()),
+        Tag::Description(zap.request().to_string()),
+    ];
+    if let Some(preimage) = preimage {
+        tags.push(Tag::Preimage(preimage));
+    }
+    for kind in [TagKind::P, TagKind::E, TagKind::A] {
+        if let Some(tag) = request.tags.iter().find(|t| t.kind() == kind) {
+            tags.push(tag.clone());
+        }
+    }
+    tags.push(Tag::Generic(
+        TagKind::Custom("P".to_string()),
+        vec![request.pubkey.to_string()],
+    ));
+
+    Ok(EventBuilder::new(Kind::ZapReceipt, "", &tags).to_event(identity)?)
+}
+
+/// The client receipts are published with, relays are added to it as zap requests list them
+pub fn receipt_client(identity: &Keys) -> Client {
+    Client::with_opts(identity, Options::new().wait_for_connection(true))
+}
+
+/// The relays in a zap request a receipt can go to. Senders pick them, so only public `wss`
+/// relays are used, the proxy shouldn't be made to connect to hosts on its own network.
+fn receipt_relays(request: &Event) -> Vec<Url> {
+    request
+        .tags
+        .iter()
+        .find_map(|t| match t {
+            Tag::Relays(relays) => Some(relays.clone()),
+            _ => None,
+        })
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|relay| Url::try_from(relay).ok())
+        .filter(is_public_relay)
+        .take(MAX_RECEIPT_RELAYS)
+        .collect()
+}
+
+fn is_public_relay(url: &Url) -> bool {
+    if url.scheme() != "wss" {
+        return false;
+    }
+    match url.host() {
+        Some(Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            let internal = ["localhost", "local", "internal", "lan", "home.arpa"]
+                .iter()
+                .any(|suffix| domain == *suffix || domain.ends_with(&format!(".{suffix}")));
+            // single label names only resolve on local networks
+            domain.contains('.') && !internal
+        }
+        Some(Host::Ipv4(ip)) => is_public_ipv4(&ip),
+        Some(Host::Ipv6(ip)) => is_public_ipv6(&ip),
+        None => false,
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || a == 0
+        // shared address space, 100.64.0.0/10
+        || (a == 100 && (64..128).contains(&b))
+        // reserved, 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(&ipv4);
+    }
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        // unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link local, fe80::/10
+        || (first & 0xffc0) == 0xfe80)
+}
+
+/// Sends a receipt to one relay, connecting the receipt client to it if it's new
+async fn send_receipt(client: &Client, relay: &Url, receipt: Event) -> anyhow::Result<()> {
+    let relays = client.relays().await;
+    if !relays.contains_key(relay) {
+        // forget relays from earlier receipts so senders can't grow the client without bound
+        if relays.len() >= MAX_RECEIPT_CLIENT_RELAYS {
+            for url in relays.keys() {
+                client.remove_relay(url.as_str()).await?;
+            }
+        }
+        client.add_relay(relay.as_str(), None).await?;
+        client.connect_relay(relay.as_str()).await?;
+    }
+    let opts = RelaySendOptions::new().timeout(Some(PUBLISH_TIMEOUT));
+    client
+        .pool()
+        .send_event_to(relay.clone(), receipt, opts)
+        .await?;
+    Ok(())
+}
+
+/// Publishes the receipt for a paid zap to the relays in its request
+async fn publish_receipt(state: &State, zap: &Zap, preimage: Option<String>) -> anyhow::Result<()> {
+    let receipt = build_receipt(&state.identity, zap, preimage)?;
+    let request = Event::from_json(zap.request())?;
+
+    let mut sends = JoinSet::new();
+    for relay in receipt_relays(&request) {
+        let client = state.zap_client.clone();
+        let receipt = receipt.clone();
+        sends.spawn(async move {
+            let sent =
+                tokio::time::timeout(PUBLISH_TIMEOUT, send_receipt(&client, &relay, receipt)).await;
+            (relay, sent)
+        });
+    }
+    let mut published = false;
+    while let Some(joined) = sends.join_next().await {
+        let Ok((relay, sent)) = joined else {
+            continue;
+        };
+        match sent {
+            Ok(Ok(())) => published = true,
+            Ok(Err(e)) => debug!(%relay, "Could not publish zap receipt: {e}"),
+            Err(_) => debug!(%relay, "Timed out publishing zap receipt"),
+        }
+    }
+    if !published {
+        return Err(anyhow!("Could not publish zap receipt to any relay"));
+    }
+
+    let conn = &mut state.db_pool.get()?;
+    Zap::set_receipt(conn, zap.payment_hash(), &receipt.id)?;
+    info!(
+        payment_hash = zap.payment_hash(),
+        receipt_id = %receipt.id,
+        "Published zap receipt"
+    );
+
+    Ok(())
+}
+
+/// Asks the user's wallet whether a zap's invoice was paid, returning its preimage if it was
+async fn lookup_zap(state: &State, zap: &Zap) -> anyhow::Result<Option<Option<String>>> {
+    let user_nwc = {
+        let conn = &mut state.db_pool.get()?;
+        UserNwc::find_by_user(conn, &zap.user_pubkey())?
+            .into_iter()
+            .next()
+    };
+    let Some(user_nwc) = user_nwc else {
+        return Ok(None);
+    };
+
+    let req = NwcRequest::new(
+        NwcMethod::LookupInvoice,
+        serde_json::json!({ "payment_hash": zap.payment_hash() }),
+    );
+    state
+        .metrics
+        .requests_forwarded
+        .with_label_values(&[NwcMethod::LookupInvoice.as_str()])
+        .inc();
+    let timeout = Duration::from_secs(state.config.request_timeout);
+    let response = state
+        .subscriber
+        .request_wallet(state, &user_nwc, &req, timeout)
+        .await?;
+    if let Some(error) = response.error {
+        return Err(anyhow!(
+            "Wallet could not look up invoice: {}",
+            error.message
+        ));
+    }
+
+    Ok(paid_preimage(&response.result.unwrap_or_default()))
+}
+
+/// Reads a `lookup_invoice` result, returning the preimage if the invoice was paid. The wallet
+/// made the invoice so it knows the preimage either way, only `settled_at` says it was paid.
+fn paid_preimage(result: &Value) -> Option<Option<String>> {
+    if result["settled_at"].is_null() {
+        return None;
+    }
+    let preimage = result["preimage"].as_str().filter(|p| !p.is_empty());
+
+    Some(preimage.map(str::to_string))
+}
+
+/// Polls users' wallets for paid zaps, for wallets that don't send notifications
+pub async fn watch_zaps(state: State) {
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let lookups = Arc::new(Semaphore::new(LOOKUP_CONCURRENCY));
+    loop {
+        interval.tick().await;
+
+        let pending = state
+            .db_pool
+            .get()
+            .map_err(anyhow::Error::from)
+            .and_then(|mut conn| {
+                let grace = chrono::Duration::from_std(EXPIRY_GRACE)?;
+                Ok(Zap::get_pending(&mut conn, chrono::Utc::now().naive_utc() - grace)?)
+            });
+        let pending = match pending {
+            Ok(pending) => pending,
+            Err(e) => {
+                error!("Error loading pending zaps: {e}");
+                continue;
+            }
+        };
+
+        // one slow wallet shouldn't hold up everyone else's receipts
+        let mut tasks = JoinSet::new();
+        for zap in pending {
+            let Ok(permit) = lookups.clone().acquire_owned().await else {
+                break;
+            };
+            let state = state.clone();
+            tasks.spawn(async move {
+                match lookup_zap(&state, &zap).await {
+                    Ok(Some(preimage)) => {
+                        if let Err(e) = publish_receipt(&state, &zap, preimage).await {
+                            error!(payment_hash = zap.payment_hash(), "{e}");
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => debug!(
+                        payment_hash = zap.payment_hash(),
+                        "Error looking up zap: {e}"
+                    ),
+                }
+                drop(permit);
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+    }
+}
+
+/// Publishes a receipt when a user's wallet notifies us a zap's invoice was paid
+pub async fn handle_notification(state: &State, event: Event) -> anyhow::Result<()> {
+    let user_nwc = {
+        let conn = &mut state.db_pool.get()?;
+        UserNwc::find_by_request_key(conn, &event.pubkey)?
+    };
+    let Some(user_nwc) = user_nwc else {
+        debug!("Notification is not from a user's wallet");
+        return Ok(());
+    };
+    let nwc = user_nwc.nwc_uri(&state.master_key)?;
+
+    let decrypted = decrypt(&nwc.secret, &event.pubkey, &event.content)?;
+    let notification: Notification = serde_json::from_str(&decrypted)?;
+    if notification.notification_type != "payment_received" {
+        return Ok(());
+    }
+    let Some(payment_hash) = notification.notification["payment_hash"].as_str() else {
+        warn!("Payment notification without a payment hash");
+        return Ok(());
+    };
+
+    let zap = {
+        let conn = &mut state.db_pool.get()?;
+        Zap::find(conn, payment_hash)?
+    };
+    // only the zapped user's wallet can tell us it was paid
+    let Some(zap) = zap.filter(|zap| zap.user_pubkey() == user_nwc.user_pubkey()) else {
+        return Ok(());
+    };
+    if zap.receipt_id().is_some() {
+        return Ok(());
+    }
+
+    let preimage = notification.notification["preimage"]
+        .as_str()
+        .filter(|p| !p.is_empty())
+        .map(str::to_string);
+    publish_receipt(state, &zap, preimage).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nostr::nips::nip57::ZapRequestData;
+    use nostr::UncheckedUrl;
+
+    #[test]
+    fn test_zap_receipt() {
+        let sender = Keys::generate();
+        let recipient = Keys::generate();
+        let data = ZapRequestData::new(
+            recipient.public_key(),
+            vec![UncheckedUrl::from("wss://relay.example.com")],
+        )
+        .amount(21_000);
+        let request = EventBuilder::new_zap_request(data)
+            .to_event(&sender)
+            .unwrap()
+            .as_json();
+
+        assert!(validate_zap_request(&request, 21_000).is_ok());
+        assert!(validate_zap_request(&request, 1_000).is_err());
+        let not_a_zap = EventBuilder::new_text_note("hi", &[])
+            .to_event(&sender)
+            .unwrap();
+        assert!(validate_zap_request(&not_a_zap.as_json(), 21_000).is_err());
+        let no_relays =
+            EventBuilder::new_zap_request(ZapRequestData::new(recipient.public_key(), vec![]))
+                .to_event(&sender)
+                .unwrap();
+        assert!(validate_zap_request(&no_relays.as_json(), 21_000).is_err());
+        let local_relays = EventBuilder::new_zap_request(ZapRequestData::new(
+            recipient.public_key(),
+            vec![UncheckedUrl::from("ws://localhost:7000")],
+        ))
+        .to_event(&sender)
+        .unwrap();
+        assert!(validate_zap_request(&local_relays.as_json(), 21_000).is_err());
+
+        let identity = Keys::generate();
+        let zap = Zap::new(
+            "00".repeat(32),
+            bitcoin::secp256k1::PublicKey::from_slice(&[2; 33]).unwrap(),
+            "lnbc".to_string(),
+            request.clone(),
+            chrono::Utc::now().naive_utc(),
+        );
+        let receipt = build_receipt(&identity, &zap, Some("11".repeat(32))).unwrap();
+        receipt.verify().unwrap();
+        assert_eq!(receipt.kind, Kind::ZapReceipt);
+        assert_eq!(receipt.pubkey, identity.public_key());
+        assert!(receipt.tags.contains(&Tag::Description(request)));
+        assert!(receipt
+            .tags
+            .contains(&Tag::PubKey(recipient.public_key(), None)));
+        assert!(receipt.tags.contains(&Tag::Generic(
+            TagKind::Custom("P".to_string()),
+            vec![sender.public_key().to_string()]
+        )));
+    }
+
+    #[test]
+    fn test_receipt_relays() {
+        let public = |url: &str| is_public_relay(&Url::parse(url).unwrap());
+        assert!(public("wss://relay.damus.io"));
+        assert!(public("wss://8.8.8.8"));
+        assert!(public("wss://[2606:4700::1111]"));
+        assert!(!public("ws://relay.damus.io"));
+        assert!(!public("https://relay.damus.io"));
+        assert!(!public("wss://localhost"));
+        assert!(!public("wss://relay.localhost"));
+        assert!(!public("wss://metadata.internal"));
+        assert!(!public("wss://relay"));
+        assert!(!public("wss://127.0.0.1:7000"));
+        assert!(!public("wss://10.0.0.5"));
+        assert!(!public("wss://169.254.169.254"));
+        assert!(!public("wss://100.100.1.1"));
+        assert!(!public("wss://[::1]"));
+        assert!(!public("wss://[fd00::1]"));
+        assert!(!public("wss://[::ffff:192.168.1.1]"));
+    }
+
+    #[test]
+    fn test_paid_preimage() {
+        // wallets can return the preimage of their own invoices before they're paid
+        let unpaid = serde_json::json!({ "preimage": "11".repeat(32), "settled_at": null });
+        assert_eq!(paid_preimage(&unpaid), None);
+        let paid = serde_json::json!({ "preimage": "11".repeat(32), "settled_at": 1700000000 });
+        assert_eq!(paid_preimage(&paid), Some(Some("11".repeat(32))));
+        let no_preimage = serde_json::json!({ "settled_at": 1700000000 });
+        assert_eq!(paid_preimage(&no_preimage), Some(None));
+    }
+}